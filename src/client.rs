@@ -1,33 +1,89 @@
+use crate::consts::{RESUME_INTERVAL, STATS_INTERVAL};
+use crate::ipfilter::{self, IpFilter};
 use crate::messages::handshake::Handshake;
 use crate::messages::messages::Message;
 use crate::messages::ops::*;
 use crate::partial::Partial;
 use crate::peerlist::Peerlist;
+use crate::proxy::Proxy;
+use crate::smartban::SmartBan;
+use crate::stats::Stats;
+use crate::storage::Allocation;
+use crate::streaming::{self, Streamer};
 use crate::torrents::Torrent;
+use crate::utils::bind_dual_stack;
 use crate::utils::queue::Queue;
+use crate::webseed::{SeedKind, WebSeed};
 use crate::worker::Worker;
 use ctrlc;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{broadcast, mpsc};
+use tokio::sync::{broadcast, mpsc, Mutex};
 
 pub struct Client<'a> {
     ndownloaders: u64,
     nlisteners: u64,
     pub torrent: &'a Torrent,
     pub handshake: Vec<u8>,
-    pub peer_list: Queue<String>,
+    pub peer_list: Queue<SocketAddr>,
     pub port: u16,
     pub partial: Partial<'a>,
+    pub stats: Arc<Mutex<Stats>>,
+    pub bans: Arc<Mutex<SmartBan>>,
+    pub filter: Arc<Mutex<IpFilter>>,
+    filter_path: Option<String>,
+    pub proxy: Option<Proxy>,
+    http_port: Option<u16>,
     channel_length: usize,
+    command_tx: mpsc::UnboundedSender<Command>,
+    command_rx: Option<mpsc::UnboundedReceiver<Command>>,
+}
+
+// what can be asked of a running client
+#[derive(Debug)]
+pub enum Command {
+    Move(PathBuf), // relocates the files to a directory
+    Resume,        // picks up after a disk error
+}
+
+// accepts the next connection that isn't filtered
+// ipv4 peers on the dual stack socket are mapped back to ipv4 addresses
+async fn accept(
+    listener: &mut TcpListener,
+    filter: &Mutex<IpFilter>,
+) -> std::io::Result<(TcpStream, SocketAddr)> {
+    loop {
+        let (socket, addr) = listener.accept().await?;
+        let addr = SocketAddr::new(ipfilter::canonical(&addr.ip()), addr.port());
+        if !filter.lock().await.is_blocked(&addr.ip()) {
+            return Ok((socket, addr));
+        }
+        println!("Refusing filtered peer {}", addr);
+    }
 }
 
 async fn listen(
     port: u16,
-    peer_q: Queue<TcpStream>,
+    incoming: bool,
+    filter: Arc<Mutex<IpFilter>>,
+    peer_q: Queue<(TcpStream, SocketAddr)>,
     mut done_q: mpsc::Receiver<()>,
     mut erx: broadcast::Receiver<()>,
 ) {
-    let mut listener = match TcpListener::bind(format!("0.0.0.0:{}", port)).await {
+    if !incoming {
+        // accepting connections would bypass the proxy
+        println!("Not accepting incoming connections");
+        return;
+    }
+
+    // fall back to ipv4 only on hosts without ipv6
+    let listener = match bind_dual_stack(port).and_then(TcpListener::from_std) {
+        Ok(l) => Ok(l),
+        Err(_) => TcpListener::bind(format!("0.0.0.0:{}", port)).await,
+    };
+    let mut listener = match listener {
         Ok(l) => l,
         Err(e) => panic!("Can't bind to port: {}", e),
     };
@@ -36,8 +92,8 @@ async fn listen(
     while let Some(()) = done_q.recv().await {
         tokio::select! {
             // TODO: rate limit
-            Ok((socket, _addr)) = listener.accept() => {
-                peer_q.push(socket).await;
+            Ok(peer) = accept(&mut listener, &filter) => {
+                peer_q.push(peer).await;
             },
             Ok(()) = erx.recv() => {
                 break
@@ -49,34 +105,80 @@ async fn listen(
 }
 
 impl<'a> Client<'a> {
-    pub async fn from(torrent: &'a Torrent, port: u16, dir: &str) -> Client<'a> {
+    // downloads to dir, moving the files to complete once finished
+    pub async fn from(
+        torrent: &'a Torrent,
+        port: u16,
+        dir: &str,
+        complete: Option<&str>,
+    ) -> Client<'a> {
         if torrent.length == 0 {
             panic!("no pieces");
         }
         let handshake = Handshake::from(&torrent).serialize();
         let mut partial = Partial::from(&torrent, dir);
-        partial.recover().await;
+        if let Some(complete) = complete {
+            partial.set_complete_dir(complete);
+        }
+        partial.recover(true).await;
 
         let n = std::cmp::max(torrent.pieces.len().await, 10);
+        let peers = partial.peers.drain(..).collect();
+        let (command_tx, command_rx) = mpsc::unbounded_channel();
 
         Client {
             port,
             partial,
+            stats: Arc::new(Mutex::new(Stats::default())),
+            bans: Arc::new(Mutex::new(SmartBan::default())),
+            filter: Arc::new(Mutex::new(IpFilter::default())),
+            filter_path: None,
+            proxy: None,
+            http_port: None,
             ndownloaders: 10,
             nlisteners: 10,
             torrent,
-            peer_list: Queue::new(),
+            peer_list: Queue::from(peers),
             handshake,
             channel_length: n,
+            command_tx,
+            command_rx: Some(command_rx),
         }
     }
 
+    // loads the IP filter at path, which is reloaded whenever it changes
+    pub fn set_ip_filter(&mut self, path: &str) {
+        let filter = IpFilter::load(path).expect("Could not read IP filter!");
+        println!("Loaded IP filter with {} ranges", filter.len());
+
+        self.filter = Arc::new(Mutex::new(filter));
+        self.filter_path = Some(path.to_string());
+    }
+
+    pub fn set_proxy(&mut self, proxy: Proxy) {
+        self.proxy = Some(proxy);
+    }
+
+    pub fn set_allocation(&mut self, mode: Allocation) {
+        self.partial.allocation = mode;
+    }
+
+    // commands sent here are carried out while the client runs
+    pub fn commands(&self) -> mpsc::UnboundedSender<Command> {
+        self.command_tx.clone()
+    }
+
+    // serves the files over HTTP on port while downloading
+    pub fn set_http_port(&mut self, port: u16) {
+        self.http_port = Some(port);
+    }
+
     // spawns downloaders and listeners
     async fn manage_workers(
         &mut self,
         mtx: mpsc::Sender<Op>,
         btx: broadcast::Sender<Op>,
-        peer_q: Queue<TcpStream>,
+        peer_q: Queue<(TcpStream, SocketAddr)>,
         mut done_q: mpsc::Sender<()>,
         download: bool,
     ) -> Vec<mpsc::Sender<Op>> {
@@ -111,21 +213,139 @@ impl<'a> Client<'a> {
             i += 1;
         }
 
+        if let Some(port) = self.http_port {
+            let (mtx1, mrx1) = mpsc::channel::<Op>(1);
+            vec_mtx.push(mtx1);
+            let s = Streamer::from_client(self, i + 1, btx.clone(), mrx1, mtx.clone());
+            tokio::spawn(streaming::serve(s, port));
+            i += 1;
+        }
+
+        // web seeds take pieces from the same queue as downloaders
+        // the client never answers them, so they come after everything with a channel
+        if download {
+            let seeds = self.torrent.url_list.iter().map(|u| (SeedKind::Files, u));
+            let scripts = self
+                .torrent
+                .http_seeds
+                .iter()
+                .map(|u| (SeedKind::Script, u));
+            for (kind, url) in seeds.chain(scripts) {
+                let mut w =
+                    WebSeed::from_client(self, i + 1, kind, url, btx.subscribe(), mtx.clone());
+                tokio::spawn(async move {
+                    w.download().await;
+                });
+                i += 1;
+            }
+        }
+
         vec_mtx
     }
 
+    // saves the fast-resume file along with the peers we know of
+    async fn save_resume(&self) {
+        let mut peers: Vec<SocketAddr> = self
+            .peer_list
+            .get_q()
+            .lock()
+            .await
+            .iter()
+            .cloned()
+            .collect();
+        peers.extend(
+            self.stats
+                .lock()
+                .await
+                .peers
+                .values()
+                .filter_map(|p| p.addr),
+        );
+        peers.sort();
+        peers.dedup();
+
+        if let Err(e) = self.partial.save(&peers).await {
+            println!("Could not save resume data: {}", e);
+        }
+    }
+
+    // relocates the files in the background, leaving peers connected
+    fn move_storage(&mut self, dir: &Path) {
+        if let Err(e) = self.partial.move_storage(dir) {
+            println!("Could not move to {}: {}", dir.display(), e);
+        }
+    }
+
+    async fn moved_to(&mut self, dir: &Path, res: std::io::Result<()>) {
+        match self.partial.moved_to(dir, res) {
+            Ok(()) => self.save_resume().await,
+            Err(e) => println!("Could not move to {}: {}", dir.display(), e),
+        }
+    }
+
+    // writes a piece and tells the workers about it
+    async fn store(&mut self, idx: u32, res: Vec<u8>, btx: &broadcast::Sender<Op>) {
+        if let Some(finished) = self.partial.update(idx, res).await {
+            // broadcast HAVE to all workers
+            btx.send(Op {
+                id: 0,
+                op_type: OpType::OpMessage(Message::Have(idx)),
+            })
+            .ok();
+
+            if finished {
+                btx.send(Op {
+                    id: 0,
+                    op_type: OpType::OpDownStop,
+                })
+                .ok();
+                self.save_resume().await;
+            }
+        }
+    }
+
     // receives pieces and signals have messages
     async fn receive(
         &mut self,
-        mut mtx: Vec<mpsc::Sender<Op>>,
+        mtx: Vec<mpsc::Sender<Op>>,
         mut mrx: mpsc::Receiver<Op>,
+        mut commands: mpsc::UnboundedReceiver<Command>,
+        mut moved: mpsc::UnboundedReceiver<(PathBuf, std::io::Result<()>)>,
         btx: broadcast::Sender<Op>,
         mut erx: broadcast::Receiver<()>,
     ) {
         let mut received: usize = 0;
+        let mut stats_interval = tokio::time::interval(STATS_INTERVAL);
+        let mut resume_interval = tokio::time::interval(RESUME_INTERVAL);
 
         loop {
             tokio::select! {
+                _ = stats_interval.tick() => {
+                    self.partial.check_disk().await;
+                    let mut stats = self.stats.lock().await;
+                    stats.disk = self.partial.disk.stats();
+                    if !stats.peers.is_empty() {
+                        print!("{}", stats);
+                    }
+                    if let Some(e) = &self.partial.error {
+                        println!("Paused: {} (type resume to retry)", e);
+                    }
+                },
+                _ = resume_interval.tick() => {
+                    self.save_resume().await;
+                },
+                Some(cmd) = commands.recv() => {
+                    match cmd {
+                        Command::Move(dir) => self.move_storage(&dir),
+                        Command::Resume => {
+                            self.partial.retry().await;
+                            self.save_resume().await;
+                        }
+                    }
+                },
+                Some((dir, res)) = moved.recv() => {
+                    self.moved_to(&dir, res).await;
+                },
                 Ok(()) = erx.recv() => {
                     // broadcast STOP to all workers
                     btx.send(Op {
@@ -139,34 +359,19 @@ impl<'a> Client<'a> {
                     match op.op_type {
                         OpType::OpRequest(i, s, len) => {
                             println!("Serving piece {} to Worker {}", i, op.id);
-                            if let Some(b) = self.partial.get(i, s, len).await {
-                                // send piece
-                                mtx[op.id as usize-1].send(Op {
-                                    id: 0,
-                                    op_type: OpType::OpMessage(Message::Piece(i, s, b)),
-                                }).await.ok();
-                            } else {
-                                mtx[op.id as usize-1].send(Op {
-                                    id: 0,
-                                    op_type: OpType::OpDisconnect,
-                                }).await.ok();
-                            }
+                            // reads don't hold up the other ops
+                            let disk = self.partial.disk.clone();
+                            let mut tx = mtx[op.id as usize-1].clone();
+                            tokio::spawn(async move {
+                                let op_type = match disk.read(op.id, i, s, len).await {
+                                    Some(b) => OpType::OpMessage(Message::Piece(i, s, b)),
+                                    None => OpType::OpDisconnect,
+                                };
+                                tx.send(Op { id: 0, op_type }).await.ok();
+                            });
                         },
                         OpType::OpPiece(idx, res) => {
-                            if let Some(finished) = self.partial.update(idx, res).await {
-                                // broadcast HAVE to all workers
-                                btx.send(Op {
-                                    id: 0,
-                                    op_type: OpType::OpMessage(Message::Have(idx))
-                                }).ok();
-
-                                if finished {
-                                    btx.send(Op {
-                                        id: 0,
-                                        op_type: OpType::OpDownStop,
-                                    }).ok();
-                                }
-                            }
+                            self.store(idx, res, &btx).await;
 
                             println!("Got piece {} from Worker {} --- {:.2}%", idx, op.id, 100f32 * (received as f32)/(self.channel_length as f32));
                             received += 1;
@@ -181,7 +386,16 @@ impl<'a> Client<'a> {
     }
 
     pub async fn serve(&mut self) {
+        // pause before downloading anything if the files won't fit
+        if !self.partial.done {
+            if let Err(e) = self.partial.allocate().await {
+                self.partial.fail(e).await;
+            }
+        }
+
         let mut peerlist = Peerlist::from(&self);
+        let commands = self.command_rx.take().expect("Already serving!");
+        let moved = self.partial.moved.take().expect("Already serving!");
 
         // channel for client <- workers
         let (mtx, mrx) = mpsc::channel(std::cmp::max(self.channel_length, 10));
@@ -210,6 +424,10 @@ impl<'a> Client<'a> {
             .await;
 
         let port = self.port;
+        let incoming = !self.proxy.as_ref().is_some_and(|p| p.force);
+        let filter = Arc::clone(&self.filter);
+        let filter_path = self.filter_path.clone();
+        let filter_erx = tx.subscribe();
 
         ctrlc::set_handler(move || {
             if let Err(_) = tx1.send(()) {
@@ -219,9 +437,23 @@ impl<'a> Client<'a> {
         .expect("Could not register SIGINT handler");
 
         tokio::join!(
-            peerlist.poll_peerlist(tx.subscribe()),
-            self.receive(vec_mtx, mrx, btx, erx),
-            listen(port, peer_q, done_rx, tx.subscribe())
+            peerlist.poll_peerlist(btx.subscribe(), tx.subscribe()),
+            self.receive(vec_mtx, mrx, commands, moved, btx, erx),
+            listen(
+                port,
+                incoming,
+                Arc::clone(&filter),
+                peer_q,
+                done_rx,
+                tx.subscribe()
+            ),
+            async move {
+                if let Some(path) = filter_path {
+                    ipfilter::watch(path, filter, filter_erx).await;
+                }
+            }
         );
+
+        self.save_resume().await;
     }
 }
@@ -1,5 +1,50 @@
 use std::time::Duration;
 
 pub const TIMEOUT: Duration = Duration::from_secs(10);
+// connections with no traffic for this long are dropped
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(150);
+pub const KEEPALIVE: Duration = Duration::from_secs(90);
 pub const BLOCKSIZE: u32 = 1 << 14;
-pub const MAXREQUESTS: u32 = 5;
+
+// bounds on the number of requests outstanding to one peer
+// MAXREQUESTS is also advertised as our reqq
+pub const MINREQUESTS: u32 = 2;
+pub const MAXREQUESTS: u32 = 250;
+// seconds of download to keep queued on top of the round trip time
+pub const QUEUE_TIME: f64 = 3.0;
+
+// a request older than this is given up on and its piece handed off
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(20);
+// a peer that unchoked us but sends nothing for this long is snubbing us
+pub const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+// a snubbing peer is asked for pieces again after this long
+pub const SNUB_RETRY: Duration = Duration::from_secs(120);
+// how often workers check their requests
+pub const TICK: Duration = Duration::from_secs(5);
+
+pub const STATS_INTERVAL: Duration = Duration::from_secs(10);
+// how often the fast-resume file is saved while running
+pub const RESUME_INTERVAL: Duration = Duration::from_secs(60);
+
+// pieces waiting to be written are capped at this many bytes
+pub const DIRTY_LIMIT: usize = 16 << 20;
+// runs of pieces are written at once up to this many bytes
+pub const FLUSH_SIZE: usize = 4 << 20;
+// bytes of recently used pieces kept in memory for seeding
+pub const CACHE_SIZE: usize = 32 << 20;
+
+// peers asked for in each announce
+pub const NUMWANT: u64 = 50;
+// announce interval used until the tracker sends one
+pub const TRACKER_INTERVAL: Duration = Duration::from_secs(30 * 60);
+// failed announces are retried after TRACKER_RETRY, doubling up to TRACKER_MAX_RETRY
+pub const TRACKER_RETRY: Duration = Duration::from_secs(15);
+pub const TRACKER_MAX_RETRY: Duration = Duration::from_secs(30 * 60);
+// UDP tracker requests are sent this many times, waiting TIMEOUT for each
+pub const UDP_ATTEMPTS: u32 = 3;
+
+// failing web seeds are retried after WEBSEED_RETRY, doubling up to WEBSEED_MAX_RETRY
+pub const WEBSEED_RETRY: Duration = Duration::from_secs(10);
+pub const WEBSEED_MAX_RETRY: Duration = Duration::from_secs(10 * 60);
+// time allowed to fetch one piece range from a web seed
+pub const WEBSEED_TIMEOUT: Duration = Duration::from_secs(60);
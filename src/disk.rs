@@ -0,0 +1,382 @@
+use crate::consts::{CACHE_SIZE, DIRTY_LIMIT, FLUSH_SIZE};
+use crate::storage::{DiskError, Storage};
+use crate::utils::bitfield::Bitfield;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tokio::sync::{Mutex, Notify};
+use tokio::task;
+
+// numbers about the disk cache for stats
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DiskStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub queue: usize, // pieces waiting to be written or read
+    pub dirty: usize, // bytes not written yet
+}
+
+impl fmt::Display for DiskStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let total = self.hits + self.misses;
+        let rate = if total == 0 {
+            0.0
+        } else {
+            100.0 * self.hits as f64 / total as f64
+        };
+        write!(
+            f,
+            "Disk --- cache hit rate {:.1}% of {} reads, queue {}, {:.1} KiB dirty",
+            rate,
+            total,
+            self.queue,
+            self.dirty as f64 / 1024.0
+        )
+    }
+}
+
+#[derive(Default)]
+struct Cache {
+    dirty: BTreeMap<u32, Arc<Vec<u8>>>, // pieces not written yet
+    dirty_bytes: usize,
+    clean: VecDeque<(u32, Arc<Vec<u8>>)>, // recently used pieces, oldest first
+    clean_bytes: usize,
+    last: HashMap<u64, u32>, // last piece each worker asked for
+    reads: usize,            // pieces being read
+    flushing: bool,
+    failed: bool, // writes stop until retry
+    error: Option<DiskError>,
+    stats: DiskStats,
+}
+
+impl Cache {
+    fn clean(&mut self, idx: u32) -> Option<Arc<Vec<u8>>> {
+        let i = self.clean.iter().position(|(x, _)| *x == idx)?;
+        let entry = self.clean.remove(i)?;
+        self.clean.push_back(entry.clone());
+        Some(entry.1)
+    }
+
+    // keeps a piece around, dropping the least recently used ones over CACHE_SIZE
+    fn keep(&mut self, idx: u32, buf: Arc<Vec<u8>>) {
+        if let Some(i) = self.clean.iter().position(|(x, _)| *x == idx) {
+            let (_, old) = self.clean.remove(i).unwrap();
+            self.clean_bytes -= old.len();
+        }
+        self.clean_bytes += buf.len();
+        self.clean.push_back((idx, buf));
+        while self.clean_bytes > CACHE_SIZE && self.clean.len() > 1 {
+            let (_, old) = self.clean.pop_front().unwrap();
+            self.clean_bytes -= old.len();
+        }
+    }
+}
+
+// reads and writes pieces on blocking threads, so disk I/O never holds up the runtime
+// written pieces are held until they are flushed, runs of them at once,
+// and read pieces are cached for seeding
+#[derive(Clone)]
+pub struct Disk {
+    storage: Arc<RwLock<Storage>>,
+    bf: Arc<Mutex<Bitfield>>,
+    cache: Arc<std::sync::Mutex<Cache>>,
+    flushed: Arc<Notify>,
+    piece_length: usize,
+    length: usize,
+}
+
+impl Disk {
+    pub fn new(
+        storage: Storage,
+        bf: Arc<Mutex<Bitfield>>,
+        piece_length: usize,
+        length: usize,
+    ) -> Disk {
+        Disk {
+            storage: Arc::new(RwLock::new(storage)),
+            bf,
+            cache: Arc::new(std::sync::Mutex::new(Cache::default())),
+            flushed: Arc::new(Notify::new()),
+            piece_length,
+            length,
+        }
+    }
+
+    pub fn storage(&self) -> RwLockReadGuard<'_, Storage> {
+        self.storage.read().unwrap()
+    }
+
+    pub fn storage_mut(&self) -> RwLockWriteGuard<'_, Storage> {
+        self.storage.write().unwrap()
+    }
+
+    // shared with blocking threads, which wait on it while the files move
+    pub fn shared_storage(&self) -> Arc<RwLock<Storage>> {
+        Arc::clone(&self.storage)
+    }
+
+    fn cache(&self) -> std::sync::MutexGuard<'_, Cache> {
+        self.cache.lock().unwrap()
+    }
+
+    fn piece_len(&self, idx: u32) -> usize {
+        let start = idx as usize * self.piece_length;
+        self.piece_length.min(self.length.saturating_sub(start))
+    }
+
+    // queues a piece to be written
+    // waits while too much is waiting, unless writes are failing
+    pub async fn write(&self, idx: u32, buf: Vec<u8>) {
+        {
+            let mut c = self.cache();
+            c.dirty_bytes += buf.len();
+            if let Some(old) = c.dirty.insert(idx, Arc::new(buf)) {
+                c.dirty_bytes -= old.len();
+            }
+        }
+        self.start_flush();
+
+        loop {
+            {
+                let c = self.cache();
+                if c.dirty_bytes <= DIRTY_LIMIT || c.failed {
+                    return;
+                }
+            }
+            self.flushed.notified().await;
+        }
+    }
+
+    fn start_flush(&self) {
+        let mut c = self.cache();
+        if c.flushing || c.failed || c.dirty.is_empty() {
+            return;
+        }
+        c.flushing = true;
+        tokio::spawn(self.clone().flush_all());
+    }
+
+    // writes dirty pieces until there are none left or a write fails
+    async fn flush_all(self) {
+        loop {
+            // the next run of consecutive pieces
+            let run: Vec<(u32, Arc<Vec<u8>>)> = {
+                let mut c = self.cache();
+                if c.failed || c.dirty.is_empty() {
+                    c.flushing = false;
+                    drop(c);
+                    self.flushed.notify();
+                    return;
+                }
+                let mut run: Vec<(u32, Arc<Vec<u8>>)> = Vec::new();
+                let mut size = 0;
+                for (idx, buf) in c.dirty.iter() {
+                    let next = run.last().map_or(*idx, |(i, _)| i + 1);
+                    if *idx != next || (size > 0 && size + buf.len() > FLUSH_SIZE) {
+                        break;
+                    }
+                    size += buf.len();
+                    run.push((*idx, Arc::clone(buf)));
+                }
+                run
+            };
+
+            let start = run[0].0 as usize * self.piece_length;
+            let storage = Arc::clone(&self.storage);
+            let bufs: Vec<Arc<Vec<u8>>> = run.iter().map(|(_, b)| Arc::clone(b)).collect();
+            let res = task::spawn_blocking(move || {
+                let storage = storage.read().unwrap();
+                if bufs.len() == 1 {
+                    return storage.write(start, &bufs[0]);
+                }
+                let buf: Vec<u8> = bufs.iter().flat_map(|b| b.iter().cloned()).collect();
+                storage.write(start, &buf)
+            })
+            .await
+            .expect("Disk thread panicked!");
+
+            let mut c = self.cache();
+            match res {
+                Ok(()) => {
+                    // just written pieces are likely to be asked for by peers
+                    for (idx, buf) in run {
+                        c.dirty.remove(&idx);
+                        c.dirty_bytes -= buf.len();
+                        c.keep(idx, buf);
+                    }
+                }
+                Err(e) => {
+                    c.failed = true;
+                    c.error = Some(e);
+                }
+            }
+            drop(c);
+            self.flushed.notify();
+        }
+    }
+
+    // waits until everything is written
+    // returns false if a write failed, see take_error
+    pub async fn flush(&self) -> bool {
+        self.start_flush();
+        loop {
+            {
+                let c = self.cache();
+                if c.failed {
+                    return false;
+                }
+                if c.dirty.is_empty() {
+                    return true;
+                }
+            }
+            self.flushed.notified().await;
+        }
+    }
+
+    // the error that stopped writes, if it hasn't been handed out yet
+    pub fn take_error(&self) -> Option<DiskError> {
+        self.cache().error.take()
+    }
+
+    // starts writing again after a failure
+    pub fn retry(&self) {
+        {
+            let mut c = self.cache();
+            c.failed = false;
+            c.error = None;
+        }
+        self.start_flush();
+    }
+
+    // pieces that aren't on disk yet
+    pub fn dirty(&self) -> Vec<u32> {
+        self.cache().dirty.keys().cloned().collect()
+    }
+
+    pub fn stats(&self) -> DiskStats {
+        let c = self.cache();
+        DiskStats {
+            queue: c.dirty.len() + c.reads,
+            dirty: c.dirty_bytes,
+            ..c.stats
+        }
+    }
+
+    // reads len bytes at offset of a piece for worker who
+    // returns None if we don't have the piece or it can't be read
+    // the next piece is read ahead once the worker moves on to a new one
+    pub async fn read(&self, who: u64, idx: u32, offset: u32, len: u32) -> Option<Vec<u8>> {
+        let ahead = {
+            let bf = self.bf.lock().await;
+            if !bf.has(idx as usize) {
+                return None;
+            }
+            bf.has(idx as usize + 1)
+        };
+
+        let sequential = {
+            let mut c = self.cache();
+            let last = c.last.insert(who, idx);
+            idx > 0 && last == Some(idx - 1)
+        };
+        if sequential && ahead {
+            let disk = self.clone();
+            tokio::spawn(async move { disk.piece(idx + 1, false).await });
+        }
+
+        let buf = self.piece(idx, true).await?;
+        let (s, e) = (offset as usize, offset as usize + len as usize);
+        buf.get(s..e).map(|b| b.to_vec())
+    }
+
+    // a whole piece, from memory if possible
+    // count says whether it is counted in the hit rate
+    async fn piece(&self, idx: u32, count: bool) -> Option<Arc<Vec<u8>>> {
+        {
+            let mut c = self.cache();
+            let cached = c.dirty.get(&idx).cloned().or_else(|| c.clean(idx));
+            if count {
+                match cached {
+                    Some(_) => c.stats.hits += 1,
+                    None => c.stats.misses += 1,
+                }
+            }
+            if cached.is_some() {
+                return cached;
+            }
+            c.reads += 1;
+        }
+
+        let storage = Arc::clone(&self.storage);
+        let (start, len) = (idx as usize * self.piece_length, self.piece_len(idx));
+        let res = task::spawn_blocking(move || storage.read().unwrap().read(start, len))
+            .await
+            .expect("Disk thread panicked!");
+
+        let mut c = self.cache();
+        c.reads -= 1;
+        let buf = Arc::new(res.ok()?);
+        c.keep(idx, Arc::clone(&buf));
+        Some(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Disk;
+    use crate::storage::Storage;
+    use crate::utils::bitfield::Bitfield;
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+
+    #[tokio::test]
+    async fn test_disk() {
+        let dir = std::env::temp_dir().join(format!("ntorrent_disk_{}", std::process::id()));
+        let files = vec![(dir.join("a"), 25), (dir.join("b"), 15)];
+        let storage = Storage::from_files(files, dir.join("x.part"));
+        let bf = Arc::new(Mutex::new(Bitfield::new(1)));
+        let disk = Disk::new(storage, Arc::clone(&bf), 10, 40);
+
+        let data: Vec<u8> = (0..40).collect();
+        for i in 0..4 {
+            disk.write(i, data[i as usize * 10..(i as usize + 1) * 10].to_vec())
+                .await;
+            bf.lock().await.add(i as usize);
+        }
+        // served from memory whether or not it was written yet
+        assert_eq!(disk.read(1, 2, 5, 5).await.unwrap(), &data[25..30]);
+        assert!(disk.flush().await);
+        assert!(disk.dirty().is_empty());
+        assert_eq!(std::fs::read(dir.join("a")).unwrap(), &data[..25]);
+        assert_eq!(std::fs::read(dir.join("b")).unwrap(), &data[25..]);
+        assert_eq!(disk.stats().hits, 1);
+
+        // not in the cache anymore
+        let fresh = Disk::new(disk.storage().clone(), Arc::clone(&bf), 10, 40);
+        assert_eq!(fresh.read(1, 0, 0, 10).await.unwrap(), &data[..10]);
+        assert_eq!(fresh.read(1, 0, 2, 3).await.unwrap(), &data[2..5]);
+        // moving on to piece 1 reads piece 2 ahead
+        assert_eq!(fresh.read(1, 1, 0, 10).await.unwrap(), &data[10..20]);
+        tokio::time::delay_for(std::time::Duration::from_millis(100)).await;
+        assert_eq!(fresh.read(1, 2, 0, 10).await.unwrap(), &data[20..30]);
+        let stats = fresh.stats();
+        assert_eq!((stats.hits, stats.misses), (2, 2));
+        assert!(fresh.read(1, 2, 5, 10).await.is_none());
+
+        // writes that fail are kept until retried
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::write(&dir, b"not a directory").unwrap();
+        disk.write(0, vec![1; 10]).await;
+        assert!(!disk.flush().await);
+        assert!(disk.take_error().is_some());
+        assert_eq!(disk.read(1, 0, 0, 2).await.unwrap(), vec![1, 1]);
+        assert_eq!(disk.dirty(), vec![0]);
+
+        std::fs::remove_file(&dir).unwrap();
+        disk.retry();
+        assert!(disk.flush().await);
+        assert_eq!(std::fs::read(dir.join("a")).unwrap()[..10], [1; 10]);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
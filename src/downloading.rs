@@ -0,0 +1,264 @@
+use crate::consts::BLOCKSIZE;
+use crate::torrents::Piece;
+use crate::utils::bitfield::Bitfield;
+use crate::utils::calc_request;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Block {
+    Missing,
+    Requested(Instant),
+    Have,
+}
+
+// a piece that is partway through being downloaded
+// keeps track of the state of every block in it
+#[derive(Debug)]
+pub struct Downloading {
+    pub piece: Piece,
+    pub buf: Vec<u8>,
+    blocks: Vec<Block>,
+    sources: Vec<Option<IpAddr>>, // who sent each block
+    have: usize,
+}
+
+impl Downloading {
+    pub fn new(piece: Piece) -> Downloading {
+        let n = piece.2.div_ceil(BLOCKSIZE) as usize;
+
+        Downloading {
+            piece,
+            buf: vec![0; piece.2 as usize],
+            blocks: vec![Block::Missing; n],
+            sources: vec![None; n],
+            have: 0,
+        }
+    }
+
+    // picks a piece back up after a restart
+    // buf holds the blocks marked in have
+    pub fn resume(piece: Piece, buf: Vec<u8>, have: &Bitfield) -> Downloading {
+        let mut d = Downloading::new(piece);
+        if buf.len() != d.buf.len() {
+            return d;
+        }
+
+        d.buf = buf;
+        for (i, b) in d.blocks.iter_mut().enumerate() {
+            if have.has(i) {
+                *b = Block::Have;
+                d.have += 1;
+            }
+        }
+        d
+    }
+
+    // the blocks that have arrived
+    pub fn have(&self) -> Bitfield {
+        let mut bf = Bitfield::new(self.blocks.len().div_ceil(8));
+        for (i, b) in self.blocks.iter().enumerate() {
+            if *b == Block::Have {
+                bf.add(i);
+            }
+        }
+        bf
+    }
+
+    // marks the next missing block as requested
+    // returns offset * length of the request
+    pub fn next_request(&mut self) -> Option<(u32, u32)> {
+        let i = self.blocks.iter().position(|b| *b == Block::Missing)?;
+        self.blocks[i] = Block::Requested(Instant::now());
+
+        let start = i as u32 * BLOCKSIZE;
+        Some((start, calc_request(start, self.piece.2)))
+    }
+
+    // marks every missing block as requested, for fetching them in one go
+    // returns offset * length of each run of consecutive blocks
+    pub fn request_missing(&mut self) -> Vec<(u32, u32)> {
+        let mut runs: Vec<(u32, u32)> = Vec::new();
+        while let Some((s, len)) = self.next_request() {
+            match runs.last_mut() {
+                Some((start, n)) if *start + *n == s => *n += len,
+                _ => runs.push((s, len)),
+            }
+        }
+        runs
+    }
+
+    // copies the blocks of runs, sent back to back in buf by a web seed
+    // returns None if buf doesn't hold exactly the runs requested
+    pub fn receive_runs(&mut self, runs: &[(u32, u32)], buf: &[u8]) -> Option<()> {
+        let total: u32 = runs.iter().map(|(_, n)| n).sum();
+        if buf.len() != total as usize {
+            return None;
+        }
+
+        let mut pos = 0;
+        for (start, n) in runs {
+            for s in (*start..start + n).step_by(BLOCKSIZE as usize) {
+                let len = calc_request(s, self.piece.2) as usize;
+                self.receive(s, &buf[pos..pos + len], None)?;
+                pos += len;
+            }
+        }
+        Some(())
+    }
+
+    // copies a block sent by from into the buffer
+    // returns time since it was requested, or None if it wasn't requested
+    pub fn receive(&mut self, offset: u32, data: &[u8], from: Option<IpAddr>) -> Option<Duration> {
+        if !offset.is_multiple_of(BLOCKSIZE)
+            || offset >= self.piece.2
+            || data.len() as u32 != calc_request(offset, self.piece.2)
+        {
+            return None;
+        }
+
+        let i = (offset / BLOCKSIZE) as usize;
+        let sent = match self.blocks[i] {
+            Block::Requested(t) => t,
+            _ => return None,
+        };
+
+        let start = offset as usize;
+        self.buf[start..start + data.len()].copy_from_slice(data);
+        self.blocks[i] = Block::Have;
+        self.sources[i] = from;
+        self.have += 1;
+
+        Some(sent.elapsed())
+    }
+
+    // returns true if any request has been in flight longer than t
+    pub fn timed_out(&self, t: Duration) -> bool {
+        self.blocks.iter().any(|b| match b {
+            Block::Requested(sent) => sent.elapsed() > t,
+            _ => false,
+        })
+    }
+
+    // offset * length of every request in flight
+    pub fn pending(&self) -> Vec<(u32, u32)> {
+        self.blocks
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| matches!(b, Block::Requested(_)))
+            .map(|(i, _)| {
+                let start = i as u32 * BLOCKSIZE;
+                (start, calc_request(start, self.piece.2))
+            })
+            .collect()
+    }
+
+    // forgets about requests that will not be answered
+    pub fn cancel_requests(&mut self) {
+        for b in self.blocks.iter_mut() {
+            if let Block::Requested(_) = b {
+                *b = Block::Missing;
+            }
+        }
+    }
+
+    // each block's data along with who sent it
+    pub fn blocks(&self) -> impl Iterator<Item = (&[u8], Option<IpAddr>)> {
+        self.buf
+            .chunks(BLOCKSIZE as usize)
+            .zip(self.sources.iter().cloned())
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.have == self.blocks.len()
+    }
+
+    // number of requests in flight
+    pub fn requests(&self) -> u32 {
+        self.blocks
+            .iter()
+            .filter(|b| matches!(b, Block::Requested(_)))
+            .count() as u32
+    }
+
+    // bytes requested but not yet received
+    pub fn outstanding(&self) -> u32 {
+        self.blocks
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| matches!(b, Block::Requested(_)))
+            .map(|(i, _)| calc_request(i as u32 * BLOCKSIZE, self.piece.2))
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Downloading;
+    use crate::consts::BLOCKSIZE;
+    use crate::torrents::Piece;
+
+    #[test]
+    fn test_requests() {
+        let mut d = Downloading::new(Piece([0; 20], 0, BLOCKSIZE + 10));
+        assert_eq!(d.next_request(), Some((0, BLOCKSIZE)));
+        assert_eq!(d.next_request(), Some((BLOCKSIZE, 10)));
+        assert_eq!(d.next_request(), None);
+        assert_eq!(d.requests(), 2);
+        assert_eq!(d.outstanding(), BLOCKSIZE + 10);
+
+        // wrong length and unrequested offsets are rejected
+        assert_eq!(d.receive(BLOCKSIZE, &[0; 9], None), None);
+        assert_eq!(d.receive(2 * BLOCKSIZE, &[0; 10], None), None);
+
+        assert!(d.receive(BLOCKSIZE, &[1; 10], None).is_some());
+        assert_eq!(d.receive(BLOCKSIZE, &[1; 10], None), None);
+        assert!(!d.is_complete());
+        assert_eq!(d.outstanding(), BLOCKSIZE);
+
+        assert!(d.receive(0, &vec![2; BLOCKSIZE as usize], None).is_some());
+        assert!(d.is_complete());
+        assert_eq!(d.buf[BLOCKSIZE as usize], 1);
+    }
+
+    #[test]
+    fn test_resume() {
+        let piece = Piece([0; 20], 0, 2 * BLOCKSIZE + 10);
+        let mut d = Downloading::new(piece);
+        d.next_request();
+        d.next_request();
+        assert!(d
+            .receive(BLOCKSIZE, &vec![1; BLOCKSIZE as usize], None)
+            .is_some());
+        assert_eq!(d.have().bf, vec![0b0100_0000]);
+
+        let mut d = Downloading::resume(piece, d.buf.clone(), &d.have());
+        assert_eq!(d.next_request(), Some((0, BLOCKSIZE)));
+        assert_eq!(d.next_request(), Some((2 * BLOCKSIZE, 10)));
+        assert_eq!(d.next_request(), None);
+        assert_eq!(d.buf[BLOCKSIZE as usize], 1);
+    }
+
+    #[test]
+    fn test_runs() {
+        let piece = Piece([0; 20], 0, 3 * BLOCKSIZE + 10);
+        let mut d = Downloading::new(piece);
+        d.next_request();
+        d.next_request();
+        assert!(d
+            .receive(BLOCKSIZE, &vec![1; BLOCKSIZE as usize], None)
+            .is_some());
+        d.cancel_requests();
+
+        let runs = d.request_missing();
+        assert_eq!(runs, vec![(0, BLOCKSIZE), (2 * BLOCKSIZE, BLOCKSIZE + 10)]);
+        assert_eq!(d.next_request(), None);
+
+        assert!(d.receive_runs(&runs, &[2; 10]).is_none());
+        let buf = vec![2; 2 * BLOCKSIZE as usize + 10];
+        assert!(d.receive_runs(&runs, &buf).is_some());
+        assert!(d.is_complete());
+        assert_eq!(d.buf[BLOCKSIZE as usize], 1);
+        assert_eq!(d.buf[3 * BLOCKSIZE as usize], 2);
+    }
+}
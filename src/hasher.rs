@@ -0,0 +1,130 @@
+use crate::torrents::Piece;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::task::{self, JoinHandle};
+
+// checks pieces against their hashes on blocking threads,
+// so SHA-1 never holds up the async runtime
+// at most one piece per core is hashed at a time
+#[derive(Clone)]
+pub struct Hasher {
+    slots: Arc<Semaphore>,
+    threads: usize,
+}
+
+impl Hasher {
+    pub fn new() -> Hasher {
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        Hasher {
+            slots: Arc::new(Semaphore::new(threads)),
+            threads,
+        }
+    }
+
+    // returns whether buf matches the piece, along with buf
+    pub async fn verify(&self, piece: Piece, buf: Vec<u8>) -> (bool, Vec<u8>) {
+        let _slot = self.slots.acquire().await;
+        task::spawn_blocking(move || (piece.verify(&buf), buf))
+            .await
+            .expect("Hashing thread panicked!")
+    }
+
+    pub fn pipeline(&self) -> Pipeline {
+        Pipeline {
+            hasher: self.clone(),
+            jobs: VecDeque::new(),
+            depth: 2 * self.threads,
+        }
+    }
+}
+
+// a piece and its data after hashing, None if it couldn't be read
+pub struct Checked {
+    pub piece: Piece,
+    pub buf: Option<Vec<u8>>,
+    pub good: bool,
+}
+
+// reads and hashes a run of pieces across all cores, handing results back in order
+// only a few pieces are held in memory at once
+pub struct Pipeline {
+    hasher: Hasher,
+    jobs: VecDeque<JoinHandle<Checked>>,
+    depth: usize,
+}
+
+impl Pipeline {
+    // queues a piece to be read by read and hashed on a blocking thread,
+    // returning the oldest result once the queue is full
+    pub async fn push<F>(&mut self, piece: Piece, read: F) -> Option<Checked>
+    where
+        F: FnOnce() -> Option<Vec<u8>> + Send + 'static,
+    {
+        let slots = Arc::clone(&self.hasher.slots);
+        self.jobs.push_back(tokio::spawn(async move {
+            let _slot = slots.acquire().await;
+            task::spawn_blocking(move || {
+                let buf = read();
+                let good = buf.as_ref().is_some_and(|b| piece.verify(b));
+                Checked { piece, buf, good }
+            })
+            .await
+            .expect("Hashing thread panicked!")
+        }));
+
+        if self.jobs.len() < self.depth {
+            return None;
+        }
+        self.next().await
+    }
+
+    // the oldest result, None once everything is handed back
+    pub async fn next(&mut self) -> Option<Checked> {
+        let job = self.jobs.pop_front()?;
+        Some(job.await.expect("Hashing task panicked!"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Hasher;
+    use crate::torrents::Piece;
+    use sha1::{Digest, Sha1};
+
+    fn piece(i: u32, data: &[u8]) -> Piece {
+        let mut hash = [0; 20];
+        hash.copy_from_slice(Sha1::digest(data).as_slice());
+        Piece(hash, i, data.len() as u32)
+    }
+
+    #[tokio::test]
+    async fn test_pipeline() {
+        let hasher = Hasher::new();
+        let (good, buf) = hasher.verify(piece(0, b"abc"), b"abc".to_vec()).await;
+        assert!(good);
+        assert_eq!(buf, b"abc");
+
+        let mut p = hasher.pipeline();
+        let mut res = Vec::new();
+        for i in 0..20 {
+            let data = vec![i as u8; 100];
+            let buf = match i % 3 {
+                0 => Some(data.clone()),
+                1 => Some(vec![0; 100]),
+                _ => None,
+            };
+            res.extend(p.push(piece(i, &data), move || buf).await);
+        }
+        while let Some(c) = p.next().await {
+            res.push(c);
+        }
+
+        assert_eq!(res.len(), 20);
+        for (i, c) in res.iter().enumerate() {
+            assert_eq!(c.piece.1, i as u32);
+            assert_eq!(c.good, i % 3 == 0);
+            assert_eq!(c.buf.is_some(), i % 3 != 2);
+        }
+    }
+}
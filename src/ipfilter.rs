@@ -0,0 +1,292 @@
+use flate2::read::GzDecoder;
+use std::io::Read;
+use std::net::{IpAddr, Ipv6Addr};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::{broadcast, Mutex};
+
+// how often the filter file is checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+// eMule ranges with an access level above this are allowed
+const EMULE_ALLOW_LEVEL: u32 = 127;
+
+// sorts ranges and merges overlapping and adjacent ones
+fn merge<T: Ord + Copy>(mut ranges: Vec<(T, T)>, next: fn(T) -> Option<T>) -> Vec<(T, T)> {
+    ranges.sort();
+
+    let mut res: Vec<(T, T)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        if let Some(last) = res.last_mut() {
+            if next(last.1).is_none_or(|n| start <= n) {
+                last.1 = last.1.max(end);
+                continue;
+            }
+        }
+        res.push((start, end));
+    }
+    res
+}
+
+fn contains<T: Ord + Copy>(ranges: &[(T, T)], x: T) -> bool {
+    let i = ranges.partition_point(|r| r.0 <= x);
+    i > 0 && ranges[i - 1].1 >= x
+}
+
+// parses dotted ipv4, allowing the zero padding used in .dat files
+fn parse_v4(s: &str) -> Option<u32> {
+    let mut res: u32 = 0;
+    let mut n = 0;
+    for part in s.trim().split('.') {
+        res = (res << 8) | part.parse::<u8>().ok()? as u32;
+        n += 1;
+    }
+    if n == 4 {
+        Some(res)
+    } else {
+        None
+    }
+}
+
+fn parse_ip(s: &str) -> Option<IpAddr> {
+    let s = s.trim();
+    if let Some(ip) = parse_v4(s) {
+        return Some(IpAddr::from(ip.to_be_bytes()));
+    }
+    s.parse::<Ipv6Addr>().ok().map(IpAddr::V6)
+}
+
+// maps ipv4 addresses seen on dual stack sockets back to ipv4
+pub fn canonical(ip: &IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(IpAddr::V6(*v6), IpAddr::V4),
+        ip => *ip,
+    }
+}
+
+// a set of blocked address ranges
+// loaded from eMule ipfilter.dat, PeerGuardian P2P or CIDR lists
+#[derive(Debug, Default)]
+pub struct IpFilter {
+    v4: Vec<(u32, u32)>,
+    v6: Vec<(u128, u128)>,
+}
+
+impl IpFilter {
+    // reads a filter file, decompressing it if gzipped
+    pub fn load(path: &str) -> std::io::Result<IpFilter> {
+        let raw = std::fs::read(path)?;
+
+        let mut text = String::new();
+        if raw.starts_with(&[0x1f, 0x8b]) {
+            GzDecoder::new(raw.as_slice()).read_to_string(&mut text)?;
+        } else {
+            text = String::from_utf8_lossy(&raw).into_owned();
+        }
+
+        let (filter, skipped) = IpFilter::parse(&text);
+        if skipped > 0 {
+            println!("Skipped {} malformed lines in {}", skipped, path);
+        }
+        Ok(filter)
+    }
+
+    // parses a filter, detecting the format of each line
+    // returns the filter and the number of lines that couldn't be parsed
+    pub fn parse(text: &str) -> (IpFilter, usize) {
+        let mut v4 = Vec::new();
+        let mut v6 = Vec::new();
+        let mut skipped = 0;
+
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+                continue;
+            }
+
+            match IpFilter::parse_line(line) {
+                Some(Some((IpAddr::V4(s), IpAddr::V4(e)))) => {
+                    v4.push((u32::from(s), u32::from(e)));
+                }
+                Some(Some((IpAddr::V6(s), IpAddr::V6(e)))) => {
+                    v6.push((u128::from(s), u128::from(e)));
+                }
+                Some(None) => {} // allowed range
+                _ => skipped += 1,
+            }
+        }
+
+        let filter = IpFilter {
+            v4: merge(v4, |x: u32| x.checked_add(1)),
+            v6: merge(v6, |x: u128| x.checked_add(1)),
+        };
+        (filter, skipped)
+    }
+
+    // returns Some(None) for ranges that are listed but allowed
+    fn parse_line(line: &str) -> Option<Option<(IpAddr, IpAddr)>> {
+        IpFilter::parse_cidr(line)
+            .map(Some)
+            .or_else(|| IpFilter::parse_emule(line))
+            .or_else(|| IpFilter::parse_p2p(line).map(Some))
+            .or_else(|| IpFilter::parse_range(line).map(Some))
+            .or_else(|| parse_ip(line).map(|ip| Some((ip, ip))))
+    }
+
+    // 10.0.0.0/8 or 2001:db8::/32
+    fn parse_cidr(line: &str) -> Option<(IpAddr, IpAddr)> {
+        let (ip, bits) = line.split_once('/')?;
+        let bits: u32 = bits.trim().parse().ok()?;
+
+        match parse_ip(ip)? {
+            IpAddr::V4(ip) if bits <= 32 => {
+                let mask = u32::MAX.checked_shl(32 - bits).unwrap_or(0);
+                let start = u32::from(ip) & mask;
+                Some((
+                    IpAddr::from(start.to_be_bytes()),
+                    IpAddr::from((start | !mask).to_be_bytes()),
+                ))
+            }
+            IpAddr::V6(ip) if bits <= 128 => {
+                let mask = u128::MAX.checked_shl(128 - bits).unwrap_or(0);
+                let start = u128::from(ip) & mask;
+                Some((IpAddr::V6(start.into()), IpAddr::V6((start | !mask).into())))
+            }
+            _ => None,
+        }
+    }
+
+    // 001.002.003.000 - 001.002.003.255 , 000 , description
+    fn parse_emule(line: &str) -> Option<Option<(IpAddr, IpAddr)>> {
+        let mut fields = line.splitn(3, ',');
+        let range = IpFilter::parse_range(fields.next()?)?;
+        let level: u32 = fields.next()?.trim().parse().ok()?;
+
+        if level > EMULE_ALLOW_LEVEL {
+            Some(None)
+        } else {
+            Some(Some(range))
+        }
+    }
+
+    // description:1.2.3.0-1.2.3.255
+    fn parse_p2p(line: &str) -> Option<(IpAddr, IpAddr)> {
+        let (_, range) = line.rsplit_once(':')?;
+        IpFilter::parse_range(range)
+    }
+
+    fn parse_range(s: &str) -> Option<(IpAddr, IpAddr)> {
+        let (start, end) = s.split_once('-')?;
+        let start = parse_ip(start)?;
+        let end = parse_ip(end)?;
+
+        if start.is_ipv4() != end.is_ipv4() || start > end {
+            return None;
+        }
+        Some((start, end))
+    }
+
+    pub fn is_blocked(&self, ip: &IpAddr) -> bool {
+        match canonical(ip) {
+            IpAddr::V4(ip) => contains(&self.v4, u32::from(ip)),
+            IpAddr::V6(ip) => contains(&self.v6, u128::from(ip)),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.v4.len() + self.v6.len()
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    Path::new(path).metadata().ok()?.modified().ok()
+}
+
+// reloads the filter whenever its file changes
+pub async fn watch(path: String, filter: Arc<Mutex<IpFilter>>, mut erx: broadcast::Receiver<()>) {
+    let mut last = modified(&path);
+
+    loop {
+        tokio::select! {
+            _ = tokio::time::delay_for(RELOAD_INTERVAL) => {},
+            Ok(()) = erx.recv() => {
+                break
+            }
+        }
+
+        let m = modified(&path);
+        if m == last {
+            continue;
+        }
+        last = m;
+
+        match IpFilter::load(&path) {
+            Ok(f) => {
+                println!("Reloaded IP filter with {} ranges", f.len());
+                *filter.lock().await = f;
+            }
+            Err(e) => println!("Could not reload IP filter: {}", e),
+        }
+    }
+
+    println!("IP filter watcher stopping");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IpFilter;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+    use std::net::IpAddr;
+
+    fn blocked(f: &IpFilter, ip: &str) -> bool {
+        f.is_blocked(&ip.parse::<IpAddr>().unwrap())
+    }
+
+    #[test]
+    fn test_formats() {
+        let text = "# comment\n\
+            001.002.003.000 - 001.002.003.255 , 000 , eMule range\n\
+            005.000.000.000 - 005.255.255.255 , 200 , allowed by level\n\
+            Some org: inc:10.0.0.0-10.0.0.9\n\
+            192.168.0.0/16\n\
+            2001:db8::/32\n\
+            8.8.8.8\n\
+            garbage\n";
+        let (f, skipped) = IpFilter::parse(text);
+        assert_eq!(skipped, 1);
+
+        assert!(blocked(&f, "1.2.3.4"));
+        assert!(!blocked(&f, "1.2.4.0"));
+        assert!(!blocked(&f, "5.1.1.1"));
+        assert!(blocked(&f, "10.0.0.9"));
+        assert!(!blocked(&f, "10.0.0.10"));
+        assert!(blocked(&f, "192.168.200.1"));
+        assert!(blocked(&f, "::ffff:192.168.0.1"));
+        assert!(blocked(&f, "2001:db8:1::1"));
+        assert!(!blocked(&f, "2001:db9::1"));
+        assert!(blocked(&f, "8.8.8.8"));
+        assert!(!blocked(&f, "8.8.8.9"));
+    }
+
+    #[test]
+    fn test_merge() {
+        let (f, _) = IpFilter::parse("1.0.0.0-1.0.0.10\n1.0.0.11-1.0.0.20\n1.0.0.5-1.0.0.6\n");
+        assert_eq!(f.len(), 1);
+        assert!(blocked(&f, "1.0.0.15"));
+    }
+
+    #[test]
+    fn test_gzip() {
+        let path = std::env::temp_dir().join("ntorrent_ipfilter_test.p2p.gz");
+        let mut e = GzEncoder::new(Vec::new(), Compression::default());
+        e.write_all(b"bad peers:6.6.6.0-6.6.6.255\n").unwrap();
+        std::fs::write(&path, e.finish().unwrap()).unwrap();
+
+        let f = IpFilter::load(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert!(blocked(&f, "6.6.6.6"));
+    }
+}
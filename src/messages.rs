@@ -1,3 +1,4 @@
+pub mod extended;
 pub mod handshake;
 pub mod messages;
 pub mod ops;
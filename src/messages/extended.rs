@@ -0,0 +1,51 @@
+use crate::consts::MAXREQUESTS;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::collections::BTreeMap;
+
+// extended message id reserved for the handshake (BEP 10)
+pub const HANDSHAKE_ID: u8 = 0;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ExtendedHandshake {
+    #[serde(default)]
+    pub m: BTreeMap<String, i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<u32>,
+}
+
+impl ExtendedHandshake {
+    pub fn new() -> ExtendedHandshake {
+        let v = format!("ntorrent {}", env!("CARGO_PKG_VERSION"));
+        ExtendedHandshake {
+            m: BTreeMap::new(),
+            v: Some(ByteBuf::from(v.into_bytes())),
+            reqq: Some(MAXREQUESTS),
+        }
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        serde_bencode::to_bytes(self).expect("Could not encode extended handshake!")
+    }
+
+    pub fn deserialize(buf: &[u8]) -> Option<ExtendedHandshake> {
+        serde_bencode::de::from_bytes(buf).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ExtendedHandshake;
+
+    #[test]
+    fn test_reqq() {
+        let h = ExtendedHandshake::deserialize(b"d1:md6:ut_pexi1ee4:reqqi500e1:pi6881ee").unwrap();
+        assert_eq!(h.reqq, Some(500));
+        assert_eq!(h.m.get("ut_pex"), Some(&1));
+
+        let h = ExtendedHandshake::new();
+        assert_eq!(ExtendedHandshake::deserialize(&h.serialize()), Some(h));
+    }
+}
@@ -1,22 +1,35 @@
 use crate::torrents::Torrent;
 
+// bit in the reserved bytes signalling the extension protocol (BEP 10)
+const EXTENSION_BYTE: usize = 5;
+const EXTENSION_BIT: u8 = 0x10;
+
 pub struct Handshake {
+    pub reserved: [u8; 8],
     pub info_hash: Vec<u8>,
     pub peer_id: Vec<u8>,
 }
 
 impl Handshake {
     pub fn from(t: &Torrent) -> Handshake {
+        let mut reserved = [0; 8];
+        reserved[EXTENSION_BYTE] |= EXTENSION_BIT;
+
         Handshake {
+            reserved,
             info_hash: t.info_hash.clone(),
             peer_id: t.peer_id.clone(),
         }
     }
 
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[EXTENSION_BYTE] & EXTENSION_BIT != 0
+    }
+
     pub fn serialize(&self) -> Vec<u8> {
         let mut res = vec![19u8];
         res.extend("BitTorrent protocol".as_bytes());
-        res.extend(vec![0; 8]);
+        res.extend(&self.reserved);
         res.extend(self.info_hash.clone());
         res.extend(self.peer_id.clone());
         res
@@ -35,7 +48,11 @@ impl Handshake {
             return None;
         }
 
+        let mut reserved = [0; 8];
+        reserved.copy_from_slice(&buf[20..28]);
+
         Some(Handshake {
+            reserved,
             info_hash: buf[28..48].to_vec(),
             peer_id: buf[48..68].to_vec(),
         })
@@ -14,6 +14,7 @@ pub enum Message {
     Piece(u32, u32, Vec<u8>), // index * offset * block
     Cancel(u32, u32, u32),
     Port(u16),
+    Extended(u8, Vec<u8>), // extended id * payload
 }
 
 impl Message {
@@ -50,6 +51,10 @@ impl Message {
                 let p = cx.read_u16::<BigEndian>().ok()?;
                 Some(Message::Port(p))
             }
+            20 => {
+                let id = cx.read_u8().ok()?;
+                Some(Message::Extended(id, cx.into_inner()[2..].to_vec()))
+            }
             _ => None,
         }
     }
@@ -91,6 +96,17 @@ impl Message {
                 WriteBytesExt::write_u32::<BigEndian>(&mut buf, s).unwrap();
                 buf.extend(payload)
             }
+            Message::Cancel(i, s, len) => {
+                WriteBytesExt::write_u8(&mut buf, 8).unwrap();
+                WriteBytesExt::write_u32::<BigEndian>(&mut buf, i).unwrap();
+                WriteBytesExt::write_u32::<BigEndian>(&mut buf, s).unwrap();
+                WriteBytesExt::write_u32::<BigEndian>(&mut buf, len).unwrap();
+            }
+            Message::Extended(id, payload) => {
+                WriteBytesExt::write_u8(&mut buf, 20).unwrap();
+                WriteBytesExt::write_u8(&mut buf, id).unwrap();
+                buf.extend(payload)
+            }
             _ => {}
         }
         buf
@@ -104,6 +120,19 @@ impl Message {
 
 #[cfg(test)]
 mod tests {
+    use super::Message;
+
     #[test]
     fn test_serialize() {}
+
+    #[test]
+    fn test_roundtrip() {
+        for m in [
+            Message::Cancel(1, 16384, 16384),
+            Message::Extended(0, b"d4:reqqi250ee".to_vec()),
+        ] {
+            let buf = m.clone().serialize();
+            assert_eq!(Message::deserialize(&buf), Some(m));
+        }
+    }
 }
@@ -2,22 +2,31 @@ use crate::consts;
 use crate::messages::messages::Message;
 use bytes::Bytes;
 use futures::{SinkExt, StreamExt};
+use std::time::{Duration, Instant};
 use tokio::net::TcpStream;
 use tokio::time::timeout;
 use tokio_util::codec::{Framed, LengthDelimitedCodec};
 
 pub struct OpStream {
     stream: Option<Framed<TcpStream, LengthDelimitedCodec>>,
+    last_sent: Instant,
+    last_received: Instant,
 }
 
 impl OpStream {
     pub fn new() -> OpStream {
-        OpStream { stream: None }
+        OpStream {
+            stream: None,
+            last_sent: Instant::now(),
+            last_received: Instant::now(),
+        }
     }
 
     pub fn from(s: TcpStream) -> OpStream {
         OpStream {
             stream: Some(Framed::new(s, LengthDelimitedCodec::new())),
+            last_sent: Instant::now(),
+            last_received: Instant::now(),
         }
     }
 
@@ -25,9 +34,25 @@ impl OpStream {
         self.stream.take();
     }
 
+    // time since anything was last sent
+    pub fn since_sent(&self) -> Duration {
+        self.last_sent.elapsed()
+    }
+
+    // time since anything was last received
+    pub fn since_received(&self) -> Duration {
+        self.last_received.elapsed()
+    }
+
+    // waits for a message until the connection is considered idle
     pub async fn read_message(&mut self) -> Option<Message> {
+        self.read_message_timeout(consts::IDLE_TIMEOUT).await
+    }
+
+    pub async fn read_message_timeout(&mut self, t: Duration) -> Option<Message> {
         if let Some(s) = &mut self.stream {
-            let buf = timeout(consts::TIMEOUT, s.next()).await.ok()??.ok()?;
+            let buf = timeout(t, s.next()).await.ok()??.ok()?;
+            self.last_received = Instant::now();
             if buf.len() == 0 {
                 return Some(Message::KeepAlive);
             }
@@ -46,6 +71,7 @@ impl OpStream {
                 eprintln!("Error writing: {}", e);
                 return None;
             }
+            self.last_sent = Instant::now();
             return Some(());
         }
         None
@@ -1,12 +1,21 @@
-use crate::torrents::Torrent;
+use crate::consts::BLOCKSIZE;
+use crate::disk::Disk;
+use crate::downloading::Downloading;
+use crate::hasher::{Checked, Hasher};
+use crate::priority::Priority;
+use crate::resume::{Resume, TrackerState, Unfinished};
+use crate::storage::{self, Allocation, DiskError, Storage};
+use crate::torrents::{Piece, Torrent};
 use crate::utils::bitfield::Bitfield;
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use std::collections::VecDeque;
-use std::fs::{remove_file, File, OpenOptions};
-use std::io::{Read, Write};
-use std::path::Path;
+use serde_bytes::ByteBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task;
 
 pub struct Progress {
     pub uploaded: usize,
@@ -14,283 +23,641 @@ pub struct Progress {
     pub left: usize,
 }
 
+// prints how far a check of n pieces is every 100 pieces
+fn report(done: usize, n: usize) {
+    if done.is_multiple_of(100) || done == n {
+        println!("Verified {:.2}%", 100f64 * done as f64 / n as f64);
+    }
+}
+
+// what a recheck found
+#[derive(Debug)]
+pub struct Recheck {
+    pub pieces: usize,
+    pub good: usize,
+    pub missing: usize,
+    pub corrupt: Vec<(String, Vec<u32>)>, // path of each file with bad pieces * those pieces
+}
+
+impl fmt::Display for Recheck {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bad = self.pieces - self.good - self.missing;
+        writeln!(
+            f,
+            "{} of {} pieces good, {} missing, {} corrupt",
+            self.good, self.pieces, self.missing, bad
+        )?;
+        for (path, pieces) in self.corrupt.iter() {
+            let pieces: Vec<String> = pieces.iter().map(|p| p.to_string()).collect();
+            writeln!(f, "Corrupt: {} (pieces {})", path, pieces.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
 pub struct Partial<'a> {
-    file: Option<File>,
-    filename: String,
-    buf: Vec<u8>,
-    received: usize,
+    pub disk: Disk,
+    dir: PathBuf,              // where the files are now
+    complete: Option<PathBuf>, // where they go once finished
+    resume_path: PathBuf,
     torrent: &'a Torrent,
-    num_pieces: usize,
     pub progress: Arc<Mutex<Progress>>,
     pub bf: Arc<Mutex<Bitfield>>,
+    pub unfinished: Arc<Mutex<HashMap<u32, Downloading>>>, // pieces handed off partway
+    pub tracker: Arc<Mutex<TrackerState>>,
+    pub hasher: Hasher,
+    pub allocation: Allocation,
+    pub peers: Vec<SocketAddr>,   // known from the last run
+    pub error: Option<DiskError>, // downloading is paused until retry
     pub done: bool,
+    moving: bool, // resume data waits until the files are in place
+    moved_tx: mpsc::UnboundedSender<(PathBuf, io::Result<()>)>,
+    pub moved: Option<mpsc::UnboundedReceiver<(PathBuf, io::Result<()>)>>, // moves that ended
 }
 
 impl<'a> Partial<'a> {
     pub fn from(torrent: &'a Torrent, dir: &str) -> Partial<'a> {
-        let filename = if dir == "" {
-            format!("{}{}", torrent.name, ".part")
-        } else {
-            format!("{}/{}{}", dir, torrent.name, ".part")
-        };
+        let path = |ext: &str| Path::new(dir).join(format!("{}.{}", torrent.name, ext));
 
         let bf_len = (torrent.length - 1) / (8 * torrent.piece_length as usize) + 1;
-        let bf = Bitfield::new(bf_len);
-        let len = (torrent.length - 1) / (torrent.piece_length as usize) + 1;
+        let bf = Arc::new(Mutex::new(Bitfield::new(bf_len)));
+        let storage = Storage::new(torrent, path("part"));
+        let (moved_tx, moved) = mpsc::unbounded_channel();
 
         Partial {
             torrent,
-            file: None,
-            filename,
-            buf: vec![0; torrent.length],
-            num_pieces: len,
-            received: 0,
+            disk: Disk::new(
+                storage,
+                Arc::clone(&bf),
+                torrent.piece_length as usize,
+                torrent.length,
+            ),
+            dir: PathBuf::from(dir),
+            complete: None,
+            resume_path: path("resume"),
             progress: Arc::new(Mutex::new(Progress {
                 downloaded: 0,
                 uploaded: 0,
                 left: torrent.length,
             })),
-            bf: Arc::new(Mutex::new(bf)),
+            bf,
+            unfinished: Arc::new(Mutex::new(HashMap::new())),
+            tracker: Arc::new(Mutex::new(TrackerState::new(&torrent.trackers))),
+            hasher: Hasher::new(),
+            allocation: Allocation::Sparse,
+            peers: Vec::new(),
+            error: None,
             done: false,
+            moving: false,
+            moved_tx,
+            moved: Some(moved),
+        }
+    }
+
+    // moves the files to dir once the wanted pieces are all in
+    // picks them up from there instead if they were already moved
+    pub fn set_complete_dir(&mut self, dir: &str) {
+        let dir = PathBuf::from(dir);
+        let mut storage = self.disk.storage().clone();
+        storage.rebase(&self.dir, &dir);
+        let resume_path = dir.join(self.resume_path.file_name().unwrap());
+
+        let found =
+            resume_path.exists() || (0..self.torrent.files.len()).any(|i| storage.path(i).exists());
+        if dir != self.dir && found {
+            println!("Found {} in {}", self.torrent.name, dir.display());
+            *self.disk.storage_mut() = storage;
+            self.resume_path = resume_path;
+            self.dir = dir.clone();
         }
+        self.complete = Some(dir);
     }
 
     // determines if there has been progress
-    pub async fn recover(&mut self) {
-        if Path::new(&self.filename).exists() {
-            let file = OpenOptions::new()
-                .read(true)
-                .append(true)
-                .create(true)
-                .open(&self.filename)
-                .ok();
-            // read in .part file
-            if self.read_part(file).await != None {
+    // without check nothing is hashed, finished or taken out of the part file,
+    // for when a recheck follows
+    pub async fn recover(&mut self, check: bool) {
+        // skipped files that aren't there yet are kept out of the way
+        {
+            let prio = self.torrent.priorities.lock().await;
+            let skipped: Vec<usize> = (0..self.torrent.files.len())
+                .filter(|i| {
+                    prio.file(*i) == Priority::Skip && !self.disk.storage().path(*i).exists()
+                })
+                .collect();
+            self.disk.storage_mut().set_part_files(&skipped);
+        }
+
+        if let Some(r) = Resume::load(&self.resume_path) {
+            if self.resume(r, check).await.is_some() {
+                if check {
+                    self.check_done().await;
+                }
                 return;
             }
+            println!("Ignoring resume data in {}", self.resume_path.display());
+        }
+
+        // a part file without resume data can't be trusted
+        self.disk.storage().remove_part();
+        let exists = (0..self.torrent.files.len()).any(|i| self.disk.storage().path(i).exists());
+        if check && exists {
+            print!("{}", self.recheck().await);
+            self.check_done().await;
+            self.save(&[]).await.ok();
+        }
+    }
 
-            // error in reading part file so erase bad file
-            remove_file(&self.filename).ok();
-        } else if let Some(true) = self.has().await {
-            // already have file
+    // the wanted files may all be there already
+    async fn check_done(&mut self) {
+        let prio = self.torrent.priorities.lock().await;
+        if prio.complete(&*self.bf.lock().await) {
+            drop(prio);
             self.done = true;
-            return;
+            self.finish().await;
         }
+    }
 
-        self.file = OpenOptions::new()
-            .read(true)
-            .append(true)
-            .create(true)
-            .open(&self.filename)
-            .ok();
+    // stops downloading after a disk error
+    // pieces that arrive in the meantime are kept in memory until retry
+    pub async fn fail(&mut self, e: DiskError) {
+        println!("Paused: {}", e);
+        self.torrent.pause(true).await;
+        self.error = Some(e);
     }
 
-    // reads a .part file and updates self
-    // returns None if anything goes wrong indicating corrupt part file
-    async fn read_part(&mut self, mut file: Option<File>) -> Option<()> {
-        let f = file.as_mut()?;
-        let num_bytes = f.metadata().ok()?.len();
-        let n = self.torrent.piece_length as u64;
+    // pauses if writing pieces in the background failed
+    pub async fn check_disk(&mut self) {
+        if let Some(e) = self.disk.take_error() {
+            self.fail(e).await;
+        }
+    }
 
-        if num_bytes % (n + 4) != 0 || num_bytes > (n + 4) * self.torrent.piece_length as u64 {
-            return None;
+    // picks up after a disk error, say once space has been freed
+    pub async fn retry(&mut self) {
+        match self.error.take() {
+            Some(e) => println!("Retrying after: {}", e),
+            None => return,
         }
 
-        let bf_len = (self.torrent.length - 1) / (8 * self.torrent.piece_length as usize) + 1;
-        let mut bf = Bitfield::new(bf_len);
+        self.torrent.pause(false).await;
+        if !self.done {
+            if let Err(e) = self.allocate().await {
+                self.fail(e).await;
+                return;
+            }
+        }
+        self.disk.retry();
+        if self.done {
+            self.finish().await;
+        }
+    }
 
-        let mut buf = vec![0; self.torrent.piece_length as usize];
-        let q = self.torrent.pieces.get_q();
-        let q = q.lock().await;
+    // relocates the files, part file and resume data to dir in the background
+    // copies to another filesystem are made while the files are still in use,
+    // and disk reads and writes only wait for the files to be swapped over
+    // once it is done, the result comes out of moved, to be passed to moved_to
+    pub fn move_storage(&mut self, dir: &Path) -> io::Result<()> {
+        if self.moving {
+            return Err(io::Error::other("Already moving"));
+        }
+        if dir == self.dir {
+            return Ok(());
+        }
+        self.moving = true;
+
+        let (from, to) = (self.dir.clone(), dir.to_path_buf());
+        let resume_path = to.join(self.resume_path.file_name().unwrap());
+        let old_resume = self.resume_path.clone();
+        // whatever made it over is read from its new place
+        let storage = self.disk.shared_storage();
+        let tx = self.moved_tx.clone();
+        task::spawn_blocking(move || {
+            let res = (|| {
+                let moves = storage.read().unwrap().moves(&from, &to)?;
+                let copied = moves
+                    .iter()
+                    .map(|(root, dest)| storage::copy_ahead(root, dest))
+                    .collect::<io::Result<Vec<_>>>()?;
+                storage.write().unwrap().finish_moves(&moves, &copied)
+            })();
+            // a fresh one is saved after the move anyway
+            if res.is_ok() && storage::move_path(&old_resume, &resume_path).is_err() {
+                std::fs::remove_file(&old_resume).ok();
+            }
+            tx.send((to, res)).ok();
+        });
+        Ok(())
+    }
 
-        println!("Recovering from {}", self.filename);
+    // picks up where the files are once a move is over
+    pub fn moved_to(&mut self, dir: &Path, res: io::Result<()>) -> io::Result<()> {
+        self.moving = false;
+        res?;
+        self.resume_path = dir.join(self.resume_path.file_name().unwrap());
+        self.dir = dir.to_path_buf();
+        println!("Moved {} to {}", self.torrent.name, dir.display());
+        Ok(())
+    }
 
-        let mut i = 0;
-        while i < num_bytes {
-            let idx = f.read_u32::<BigEndian>().ok()? as usize;
-            let start = idx * self.torrent.piece_length as usize;
+    // moves finished files to the complete directory, if there is one
+    fn move_complete(&mut self) {
+        if let Some(dir) = self.complete.clone() {
+            if let Err(e) = self.move_storage(&dir) {
+                println!(
+                    "Could not move {} to {}: {}",
+                    self.torrent.name,
+                    dir.display(),
+                    e
+                );
+            }
+        }
+    }
+
+    // picks up from saved resume data
+    // the pieces it claims are checked again if the files changed since it was saved
+    // and check is set
+    // returns None if the data is for something else
+    async fn resume(&mut self, r: Resume, check: bool) -> Option<()> {
+        let n = self.torrent.files.len();
+        let bf_len = self.bf.lock().await.len();
+        if r.info_hash.as_slice() != self.torrent.info_hash.as_slice()
+            || r.bitfield.len() != bf_len
+            || r.files.len() != n
+            || r.part_files.iter().any(|i| *i >= n)
+        {
+            return None;
+        }
+
+        let mut part_files = self.disk.storage().part_files();
+        part_files.extend(r.part_files.iter().cloned());
+        self.disk.storage_mut().set_part_files(&part_files);
 
-            // check if a double piece (would be bad)
-            if bf.has(idx) {
-                return None;
+        let trusted = {
+            let storage = self.disk.storage();
+            r.files == storage.states() && r.part == storage.part_state()
+        };
+        let mut bf = Bitfield::from(r.bitfield.into_vec());
+
+        if trusted {
+            println!("Resuming from {}", self.resume_path.display());
+        } else if check {
+            println!("Files changed since the last run, checking pieces");
+            let claimed: Vec<Piece> = self
+                .torrent
+                .hashes
+                .iter()
+                .filter(|p| bf.has(p.1 as usize))
+                .cloned()
+                .collect();
+
+            let mut checked = Bitfield::new(bf_len);
+            let mut n = 0;
+            let mut tally = |c: Checked| {
+                if c.good {
+                    checked.add(c.piece.1 as usize);
+                }
+                n += 1;
+                report(n, claimed.len());
+            };
+
+            let mut checks = self.hasher.pipeline();
+            for piece in claimed.iter() {
+                if let Some(c) = checks.push(*piece, self.piece_reader(piece)).await {
+                    tally(c);
+                }
+            }
+            while let Some(c) = checks.next().await {
+                tally(c);
             }
+            bf = checked;
+        }
 
-            bf.add(idx);
-            f.read_exact(buf.as_mut_slice()).ok()?;
+        // the blocks of unfinished pieces were written in place
+        let mut unfinished = HashMap::new();
+        for u in r.unfinished.into_iter().filter(|_| trusted) {
+            let piece = match self.torrent.hashes.get(u.piece as usize) {
+                Some(p) if !bf.has(p.1 as usize) => *p,
+                _ => continue,
+            };
+            let have = Bitfield::from(u.blocks.into_vec());
+            if let Some(buf) = self.read_blocks(&piece, &have) {
+                unfinished.insert(piece.1, Downloading::resume(piece, buf, &have));
+            }
+        }
 
-            // verify
-            if !q.get(idx)?.verify(&buf) {
-                return None;
+        // files that are wanted now come out of the part file
+        // a recheck leaves them be, so checking never creates files
+        if check {
+            let prio = self.torrent.priorities.lock().await;
+            for i in r.part_files {
+                if prio.file(i) != Priority::Skip {
+                    self.disk.storage_mut().unpart(i).ok()?;
+                }
             }
-            self.buf[start..start + n as usize].copy_from_slice(&buf);
+        }
 
-            i += n + 4;
+        self.remove_pieces(&bf).await;
+        self.torrent.priorities.lock().await.set_have(&bf);
+        *self.bf.lock().await = bf;
+        self.unfinished = Arc::new(Mutex::new(unfinished));
+        // new trackers, e.g. after a passkey change, get a fresh key and no old tracker id
+        // peers of a private torrent may only come from its current trackers,
+        // so they are dropped too if older resume data didn't record them
+        let known = !r.tracker.trackers.is_empty();
+        let same = r.tracker.trackers == self.torrent.trackers;
+        if same || !(known || self.torrent.private) {
+            let mut tracker = r.tracker;
+            tracker.trackers = self.torrent.trackers.clone();
+            self.tracker = Arc::new(Mutex::new(tracker));
+        } else if known {
+            println!("Trackers changed, announcing afresh");
+        }
+        if same || !self.torrent.private {
+            self.peers = r.peers.iter().filter_map(|p| p.parse().ok()).collect();
         }
-        drop(q);
 
-        // get rid of pieces that we now have
-        let mut newq = VecDeque::new();
-        for i in 0..self.torrent.pieces.len().await {
-            let piece = self.torrent.pieces.pop_block().await;
+        Some(())
+    }
+
+    // reads a piece from disk when called, None if it isn't all there
+    // for reading on a blocking thread
+    fn piece_reader(&self, piece: &Piece) -> impl FnOnce() -> Option<Vec<u8>> + Send + 'static {
+        let storage = self.disk.shared_storage();
+        let start = piece.1 as usize * self.torrent.piece_length as usize;
+        let len = piece.2 as usize;
+        move || storage.read().unwrap().read(start, len).ok()
+    }
 
-            if !bf.has(i) {
-                newq.push_back(piece);
+    // reads back the blocks of a piece marked in have
+    fn read_blocks(&self, piece: &Piece, have: &Bitfield) -> Option<Vec<u8>> {
+        let start = piece.1 as usize * self.torrent.piece_length as usize;
+        let mut buf = vec![0; piece.2 as usize];
+
+        let storage = self.disk.storage();
+        for (i, block) in buf.chunks_mut(BLOCKSIZE as usize).enumerate() {
+            if have.has(i) {
+                let offset = start + i * BLOCKSIZE as usize;
+                block.copy_from_slice(&storage.read(offset, block.len()).ok()?);
             }
         }
-        self.torrent.pieces.replace(newq).await;
+        Some(buf)
+    }
 
-        self.bf = Arc::new(Mutex::new(bf));
-        self.file = file;
-        self.received = (num_bytes / (n + 4)) as usize;
+    // takes the pieces in bf off the work queue and counts what is left
+    async fn remove_pieces(&self, bf: &Bitfield) {
+        let q = self.torrent.pieces.get_q();
+        let mut q = q.lock().await;
+        q.retain(|p| !bf.has(p.1 as usize));
 
-        Some(())
+        let mut p = self.progress.lock().await;
+        p.left = q.iter().map(|x| x.2 as usize).sum();
     }
 
-    // returns None if no files exist
-    // Some(true) if all files exist and are verified
-    // Some(false) otherwise
-    async fn has(&mut self) -> Option<bool> {
-        let mut all = true;
-        let mut exists = false;
-        let mut i = 0;
-        let mut buf = vec![0; self.torrent.length];
-
-        for f in self.torrent.files.iter() {
-            let path = f.path.join("/");
-            let path = Path::new(path.as_str());
-
-            // see if path exist
-            if path.exists() {
-                exists = true;
-                let file = std::fs::read(path);
-                if let Ok(v) = file {
-                    if v.len() == f.length {
-                        buf[i..i + f.length as usize].copy_from_slice(v.as_slice());
-                    } else {
-                        all = false;
-                    }
-                } else {
-                    all = false;
-                }
-            } else {
-                all = false;
+    // hashes every piece against the files on disk and starts over from what is there,
+    // so only missing and corrupt pieces are downloaded
+    // pieces count as progress as they are verified
+    pub async fn recheck(&mut self) -> Recheck {
+        let n = self.torrent.hashes.len();
+        let claimed = {
+            let mut bf = self.bf.lock().await;
+            let len = bf.len();
+            std::mem::replace(&mut *bf, Bitfield::new(len))
+        };
+        self.torrent
+            .priorities
+            .lock()
+            .await
+            .set_have(&Bitfield::new(claimed.len()));
+        self.unfinished.lock().await.clear();
+        self.progress.lock().await.left = self.torrent.length;
+
+        let mut res = Recheck {
+            pieces: n,
+            good: 0,
+            missing: 0,
+            corrupt: Vec::new(),
+        };
+        let mut corrupt = BTreeMap::new();
+
+        // pieces are read and hashed on blocking threads, several at a time
+        let torrent = self.torrent;
+        let mut checks = self.hasher.pipeline();
+        for piece in torrent.hashes.iter() {
+            if let Some(c) = checks.push(*piece, self.piece_reader(piece)).await {
+                self.tally(c, &claimed, &mut res, &mut corrupt).await;
             }
-            i += f.length;
-        }
-
-        if !exists {
-            None
-        } else {
-            if all {
-                // verify all pieces
-                {
-                    let q = self.torrent.pieces.get_q();
-                    let q = q.lock().await;
-                    let mut iter = q.iter();
-                    let mut i: u64 = 0;
-                    let len = self.torrent.piece_length as usize;
-
-                    while let Some(piece) = iter.next() {
-                        let ceil = std::cmp::min(i as usize + len, self.torrent.length);
-                        if !piece.verify(&buf[i as usize..ceil]) {
-                            // bad piece
-                            return Some(false);
-                        } else {
-                            if (i / (len as u64)) % 100 == 99 {
-                                println!(
-                                    "Verified {:.2}%",
-                                    100f64 * (i + len as u64) as f64 / self.torrent.length as f64
-                                );
-                            }
-                        }
-                        i += len as u64;
-                    }
-                }
+        }
+        while let Some(c) = checks.next().await {
+            self.tally(c, &claimed, &mut res, &mut corrupt).await;
+        }
+
+        // only what is still missing is left to do
+        let bf = self.bf.lock().await;
+        let todo = self
+            .torrent
+            .hashes
+            .iter()
+            .filter(|p| !bf.has(p.1 as usize))
+            .cloned()
+            .collect();
+        drop(bf);
+        self.torrent.pieces.replace(todo).await;
+
+        res.corrupt = corrupt
+            .into_iter()
+            .map(|(i, pieces)| (self.disk.storage().path(i).display().to_string(), pieces))
+            .collect();
+        res
+    }
 
-                // all pieces verified
-                self.buf = buf;
-                let mut p = self.progress.lock().await;
-                p.left = 0;
+    // lays out the wanted files before downloading
+    // fails if they won't fit in the space left on disk
+    pub async fn allocate(&self) -> Result<(), DiskError> {
+        let wanted: Vec<bool> = {
+            let prio = self.torrent.priorities.lock().await;
+            (0..self.torrent.files.len())
+                .map(|i| prio.file(i) != Priority::Skip)
+                .collect()
+        };
 
-                let mut bf = self.bf.lock().await;
-                let len = bf.len();
-                *bf = Bitfield::from(vec![255; len]);
+        // full allocation writes out whole files, so it runs on a blocking thread
+        let storage = self.disk.shared_storage();
+        let (dir, mode) = (self.dir.clone(), self.allocation);
+        task::spawn_blocking(move || {
+            let storage = storage.read().unwrap();
+            let wanted = |i: usize| wanted[i];
+            let needed = storage.needed(wanted);
+            if let Some(free) = storage::free_space(storage.path(0)) {
+                if needed > free {
+                    let msg = format!("{} bytes needed but only {} free", needed, free);
+                    let err = io::Error::new(io::ErrorKind::StorageFull, msg);
+                    return Err(DiskError::new("allocate", &dir, err));
+                }
+            }
+            storage.allocate(mode, wanted)
+        })
+        .await
+        .expect("Disk thread panicked!")
+    }
 
-                self.torrent.pieces.clear().await;
+    // counts a piece checked by recheck
+    async fn tally(
+        &self,
+        c: Checked,
+        claimed: &Bitfield,
+        res: &mut Recheck,
+        corrupt: &mut BTreeMap<usize, Vec<u32>>,
+    ) {
+        let idx = c.piece.1 as usize;
+        match c.buf {
+            Some(buf) if c.good => {
+                res.good += 1;
+                self.bf.lock().await.add(idx);
+                self.torrent.priorities.lock().await.add(idx);
+                self.progress.lock().await.left -= buf.len();
             }
-            Some(all)
+            // a hole in a sparse file was just never written
+            Some(buf) if claimed.has(idx) || buf.iter().any(|b| *b != 0) => {
+                let start = idx * self.torrent.piece_length as usize;
+                for file in self.disk.storage().files_in(start, buf.len()) {
+                    corrupt.entry(file).or_default().push(c.piece.1);
+                }
+            }
+            _ => res.missing += 1,
         }
+        report(idx + 1, res.pieces);
     }
 
-    // updates bitfield and writes piece to file
+    // updates bitfield and queues piece to be written to its place on disk
     // returns None if already has piece
     // returns Some(true) if finished
     pub async fn update(&mut self, idx: u32, res: Vec<u8>) -> Option<bool> {
-        let mut bf = self.bf.lock().await;
         // check if already has piece
-        if bf.has(idx as usize) {
+        if self.bf.lock().await.has(idx as usize) {
             return None;
         }
 
-        // write to buffer
-        let start = idx as usize * self.torrent.piece_length as usize;
-        self.buf[start..start + res.len()].copy_from_slice(res.as_slice());
-        self.received += 1;
+        let len = res.len();
+        self.disk.write(idx, res).await;
+        self.check_disk().await;
 
         // mark bit
+        let mut bf = self.bf.lock().await;
         bf.add(idx as usize);
 
         let mut prog = self.progress.lock().await;
-        prog.downloaded += res.len();
-        prog.left -= res.len();
+        prog.downloaded += len;
+        prog.left -= len;
 
-        // write to partial file
-        if let Some(f) = self.file.as_mut() {
-            // TODO: maybe error check this
-            f.write_u32::<BigEndian>(idx).ok();
-            f.write_all(res.as_slice()).ok();
+        let mut prio = self.torrent.priorities.lock().await;
+        prio.add(idx as usize);
+        if !prio.complete(&bf) {
+            return Some(false);
         }
 
-        if self.received == self.num_pieces {
-            self.done = true;
-            self.write_file();
-            Some(true)
-        } else {
-            Some(false)
-        }
+        // workers may need these while the files move
+        drop(prio);
+        drop(prog);
+        drop(bf);
+        self.done = true;
+        self.finish().await;
+        Some(true)
     }
 
-    // returns byte piece if present
-    pub async fn get(&self, idx: u32, offset: u32, len: u32) -> Option<Vec<u8>> {
-        let bf = self.bf.lock().await;
-        if !bf.has(idx as usize) {
-            return None;
+    // writes the fast-resume file, along with the blocks of unfinished pieces
+    // pieces still waiting to be written aren't counted
+    pub async fn save(&self, peers: &[SocketAddr]) -> io::Result<()> {
+        // saved next to the files once they have moved
+        if self.moving {
+            return Ok(());
+        }
+        self.disk.flush().await;
+
+        let mut unfinished = Vec::new();
+        let mut runs = Vec::new(); // offset * data of the blocks to write
+        for (idx, d) in self.unfinished.lock().await.iter() {
+            let have = d.have();
+            // runs of blocks are written at once
+            let mut start = *idx as usize * self.torrent.piece_length as usize;
+            let mut run = Vec::new();
+            for (i, (block, _)) in d.blocks().enumerate() {
+                if have.has(i) {
+                    run.extend_from_slice(block);
+                    continue;
+                }
+                let len = run.len();
+                if len > 0 {
+                    runs.push((start, std::mem::take(&mut run)));
+                }
+                start += len + block.len();
+            }
+            if !run.is_empty() {
+                runs.push((start, run));
+            }
+            unfinished.push(Unfinished {
+                piece: *idx,
+                blocks: ByteBuf::from(have.bf),
+            });
         }
 
-        let start = (offset + idx * self.torrent.piece_length) as usize;
-        return Some(self.buf[start..start + len as usize].to_vec());
-    }
-
-    fn write_file(&self) {
-        let mut i = 0;
-
-        for f in self.torrent.files.iter() {
-            let path = &f.path.join("/");
-            println!("Writing to {}", path);
-            let path = Path::new(&path);
+        let mut bitfield = self.bf.lock().await.bf.clone();
+        for idx in self.disk.dirty() {
+            bitfield[idx as usize / 8] &= !(1 << (7 - idx % 8));
+        }
+        let mut r = Resume {
+            info_hash: ByteBuf::from(self.torrent.info_hash.clone()),
+            bitfield: ByteBuf::from(bitfield),
+            files: Vec::new(),
+            part: Default::default(),
+            part_files: Vec::new(),
+            unfinished,
+            peers: peers.iter().map(|p| p.to_string()).collect(),
+            tracker: self.tracker.lock().await.clone(),
+            allocation: self.allocation.to_string(),
+        };
 
-            // create directories if necessary
-            let prefix = path.parent().unwrap();
-            std::fs::create_dir_all(prefix).expect("Could not create directories");
+        // the files are stated once the blocks are written
+        let storage = self.disk.shared_storage();
+        let path = self.resume_path.clone();
+        task::spawn_blocking(move || {
+            let storage = storage.read().unwrap();
+            for (start, run) in runs {
+                storage.write(start, &run)?;
+            }
+            r.files = storage.states();
+            r.part = storage.part_state();
+            r.part_files = storage.part_files();
+            r.save(&path)
+        })
+        .await
+        .expect("Disk thread panicked!")
+    }
 
-            let mut file =
-                File::create(&path).expect(&format!("Could not create {}", path.to_str().unwrap()));
+    // the wanted pieces are all in
+    async fn finish(&mut self) {
+        if !self.disk.flush().await {
+            self.check_disk().await;
+            return;
+        }
+        let res = {
+            let prio = self.torrent.priorities.lock().await;
+            let wanted = |i| prio.file(i) != Priority::Skip;
+            let storage = self.disk.storage();
+            storage
+                .create_empty(wanted)
+                .and_then(|_| storage.set_executables(wanted))
+        };
+        if let Err(e) = res {
+            self.fail(e).await;
+            return;
+        }
 
-            file.write(&self.buf[i..i + f.length])
-                .expect("Could not write to file");
-            i += f.length;
+        // keep pieces of skipped files around in case they are wanted later
+        if self.disk.storage().part_files().is_empty() {
+            self.disk.storage().remove_part();
         }
-        remove_file(self.filename.clone()).ok();
         println!("FINISHED");
+        self.move_complete();
     }
 }
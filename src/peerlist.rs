@@ -1,94 +1,356 @@
 use crate::client::Client;
+use crate::consts::{NUMWANT, TIMEOUT, TRACKER_INTERVAL, TRACKER_MAX_RETRY, TRACKER_RETRY};
+use crate::ipfilter::IpFilter;
+use crate::messages::ops::{Op, OpType};
 use crate::partial::Progress;
+use crate::proxy::Proxy;
+use crate::resume::TrackerState;
+use crate::scrape;
+use crate::stats::Stats;
 use crate::utils::queue::Queue;
 use crate::utils::serialize_bytes;
 use byteorder::{BigEndian, ReadBytesExt};
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
 use std::collections::VecDeque;
+use std::fmt;
 use std::io::Cursor;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, UdpSocket};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::{broadcast, Mutex};
+use tokio::time::{delay_until, timeout, Instant};
 
+// a peer in a non-compact peer list
+// ip is an ipv4 or ipv6 address or a DNS name
 #[derive(Serialize, Deserialize, Debug)]
+struct PeerInfo {
+    pub ip: String,
+    pub port: u16,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+enum Peers {
+    Compact(ByteBuf),
+    Dict(Vec<PeerInfo>),
+}
+
+impl Default for Peers {
+    fn default() -> Peers {
+        Peers::Compact(ByteBuf::new())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
 struct TrackerResponse {
-    pub interval: u64,
-    pub peers: ByteBuf,
+    #[serde(rename = "failure reason")]
+    pub failure_reason: Option<String>,
+    #[serde(rename = "warning message")]
+    pub warning_message: Option<String>,
+    pub interval: Option<u64>,
+    #[serde(rename = "min interval")]
+    pub min_interval: Option<u64>,
+    #[serde(rename = "tracker id")]
+    pub tracker_id: Option<ByteBuf>,
+    pub complete: Option<u64>,
+    pub incomplete: Option<u64>,
+    pub peers: Peers,
+    pub peers6: Option<ByteBuf>,
+}
+
+#[derive(Debug)]
+pub enum TrackerError {
+    Request(reqwest::Error),
+    Parse(serde_bencode::Error),
+    Io(std::io::Error),
+    Failure(String), // failure reason sent by the tracker
+    Other(String),
+}
+
+impl fmt::Display for TrackerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrackerError::Request(e) => write!(f, "request failed: {}", e),
+            TrackerError::Parse(e) => write!(f, "bad response: {}", e),
+            TrackerError::Io(e) => write!(f, "{}", e),
+            TrackerError::Failure(reason) => write!(f, "tracker refused: {}", reason),
+            TrackerError::Other(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl From<reqwest::Error> for TrackerError {
+    fn from(e: reqwest::Error) -> TrackerError {
+        TrackerError::Request(e)
+    }
+}
+
+impl From<serde_bencode::Error> for TrackerError {
+    fn from(e: serde_bencode::Error) -> TrackerError {
+        TrackerError::Parse(e)
+    }
+}
+
+impl From<std::io::Error> for TrackerError {
+    fn from(e: std::io::Error) -> TrackerError {
+        TrackerError::Io(e)
+    }
+}
+
+fn parse_response(buf: &[u8]) -> Result<TrackerResponse, TrackerError> {
+    let res: TrackerResponse = serde_bencode::de::from_bytes(buf)?;
+    match res.failure_reason {
+        Some(reason) => Err(TrackerError::Failure(reason)),
+        None => Ok(res),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Event {
+    Started,
+    Completed,
+    Stopped,
+}
+
+impl Event {
+    fn as_str(self) -> &'static str {
+        match self {
+            Event::Started => "started",
+            Event::Completed => "completed",
+            Event::Stopped => "stopped",
+        }
+    }
 }
 
 pub struct Peerlist {
     progress: Arc<Mutex<Progress>>,
-    interval: u64,
+    filter: Arc<Mutex<IpFilter>>,
+    stats: Arc<Mutex<Stats>>,
+    interval: Duration,
+    min_interval: Duration,
+    failures: u32,                     // announces failed in a row
+    tracker: Arc<Mutex<TrackerState>>, // tracker id and key, kept across runs
+    started: bool,
+    completed: bool, // finished but not yet announced
     announce: String,
     info_hash: Vec<u8>,
     peer_id: Vec<u8>,
     port: u16,
-    list: Queue<String>,
+    proxy: Option<Proxy>,
+    ipv6: Option<Ipv6Addr>, // sent to HTTP trackers, found once at startup
+    list: Queue<SocketAddr>,
+    client: reqwest::Client,
 }
 
-fn parse_peerlist(buf: &[u8]) -> VecDeque<String> {
-    if buf.len() % 6 != 0 {
-        panic!("Peer list not correct length!");
-    }
-
-    let n = buf.len() / 6;
-    let mut cx = Cursor::new(buf);
+// parses compact peers, 6 bytes each for ipv4 and 18 bytes for ipv6
+// a truncated entry at the end is ignored
+fn parse_peerlist(buf: &[u8], v6: bool) -> VecDeque<SocketAddr> {
+    let n = if v6 { 16 } else { 4 };
     let mut res = VecDeque::new();
 
-    for _ in 0..n {
-        let mut s = String::new();
-        for _ in 0..4 {
-            s.push_str(&format!("{}.", cx.read_u8().unwrap()));
-        }
-        s.pop();
-        s.push(':');
-        s.push_str(&format!("{}", cx.read_u16::<BigEndian>().unwrap()));
+    for entry in buf.chunks_exact(n + 2) {
+        let ip = if v6 {
+            let mut b = [0; 16];
+            b.copy_from_slice(&entry[..n]);
+            IpAddr::from(b)
+        } else {
+            let mut b = [0; 4];
+            b.copy_from_slice(&entry[..n]);
+            IpAddr::from(b)
+        };
+        let port = Cursor::new(&entry[n..]).read_u16::<BigEndian>().unwrap();
 
-        res.push_back(s);
+        res.push_back(SocketAddr::new(ip, port));
     }
     res
 }
 
+// finds our global ipv6 address, if any, to tell the tracker about
+// connecting a UDP socket sends nothing but picks the outgoing address
+fn local_ipv6() -> Option<Ipv6Addr> {
+    let socket = UdpSocket::bind("[::]:0").ok()?;
+    socket.connect("[2001:4860:4860::8888]:80").ok()?;
+
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V6(ip) if (ip.segments()[0] & 0xe000) == 0x2000 => Some(ip),
+        _ => None,
+    }
+}
+
+// HTTP client for tracker requests, going through the proxy if set
+pub fn tracker_client(proxy: Option<&Proxy>) -> reqwest::Client {
+    let mut builder = reqwest::Client::builder();
+    if let Some(p) = proxy {
+        builder = builder.proxy(p.reqwest().expect("Bad proxy!"));
+    }
+    builder.build().expect("Could not build HTTP client!")
+}
+
 impl Peerlist {
     pub fn from(c: &Client) -> Peerlist {
         Peerlist {
             list: c.peer_list.clone(),
             progress: Arc::clone(&c.partial.progress),
-            interval: 0,
+            filter: Arc::clone(&c.filter),
+            stats: Arc::clone(&c.stats),
+            interval: TRACKER_INTERVAL,
+            min_interval: Duration::from_secs(0),
+            failures: 0,
+            tracker: Arc::clone(&c.partial.tracker),
+            started: false,
+            completed: false,
             port: c.port,
+            proxy: c.proxy.clone(),
+            // announcing our address would go around the proxy
+            ipv6: if c.proxy.is_none() {
+                local_ipv6()
+            } else {
+                None
+            },
             info_hash: c.torrent.info_hash.clone(),
             peer_id: c.torrent.peer_id.clone(),
             announce: c.torrent.announce.clone(),
+            client: tracker_client(c.proxy.as_ref()),
         }
     }
 
-    pub async fn poll_peerlist(&mut self, mut erx: broadcast::Receiver<()>) {
+    pub async fn poll_peerlist(
+        &mut self,
+        mut brx: broadcast::Receiver<Op>,
+        mut erx: broadcast::Receiver<()>,
+    ) {
         loop {
+            let event = if !self.started {
+                Some(Event::Started)
+            } else if self.completed {
+                Some(Event::Completed)
+            } else {
+                None
+            };
+
             println!("Getting peerlist");
-            self.get_peerlist().await;
-            println!("Got peerlist");
-            tokio::select! {
-                _ = tokio::time::delay_for(Duration::from_secs(self.interval)) => {
-                },
-                Ok(()) = erx.recv() => {
-                    break
+            let wait = match self.get_peerlist(event).await {
+                Ok(()) => {
+                    println!("Got peerlist");
+                    self.failures = 0;
+                    self.started = true;
+                    if event == Some(Event::Completed) {
+                        self.completed = false;
+                    } else if self.completed {
+                        // finished before the tracker heard we started
+                        continue;
+                    }
+                    self.scrape().await;
+                    self.interval.max(self.min_interval)
+                }
+                Err(e) => {
+                    self.failures += 1;
+                    let wait = self.backoff().max(self.min_interval);
+                    println!("Tracker error: {}, retrying in {:?}", e, wait);
+                    wait
+                }
+            };
+
+            // wait for the next announce, or announce right away on finishing
+            let deadline = Instant::now() + wait;
+            loop {
+                tokio::select! {
+                    _ = delay_until(deadline) => break,
+                    Ok(op) = brx.recv() => {
+                        if op.op_type == OpType::OpDownStop {
+                            // finishing a selection of files isn't a completed download
+                            self.completed = self.progress.lock().await.left == 0;
+                            break;
+                        }
+                    },
+                    Ok(()) = erx.recv() => {
+                        self.stop().await;
+                        println!("Peerlist stopping");
+                        return;
+                    }
                 }
             }
         }
+    }
+
+    // updates the swarm stats from a scrape, when the tracker supports it
+    async fn scrape(&self) {
+        let res = scrape::scrape(
+            &self.announce,
+            &self.info_hash,
+            &self.client,
+            self.proxy.as_ref(),
+        )
+        .await;
+        if let Ok(info) = res {
+            self.stats.lock().await.swarm = Some(info);
+        }
+    }
+
+    // tells the tracker we are leaving, giving up after a timeout
+    async fn stop(&mut self) {
+        if !self.started {
+            return;
+        }
+        match timeout(TIMEOUT, self.get_peerlist(Some(Event::Stopped))).await {
+            Ok(Ok(())) => println!("Announced stop to tracker"),
+            Ok(Err(e)) => println!("Tracker error: {}", e),
+            Err(_) => println!("Tracker did not respond to stop"),
+        }
+    }
 
-        println!("Peerlist stopping");
+    // doubles the retry delay with every failed announce
+    fn backoff(&self) -> Duration {
+        let n = self.failures.saturating_sub(1).min(16);
+        (TRACKER_RETRY * 2u32.pow(n)).min(TRACKER_MAX_RETRY)
     }
 
-    async fn get_peerlist(&mut self) {
+    // turns a peer list of either form into addresses
+    // names are resolved here unless that would go around the proxy
+    async fn resolve(&self, peers: Peers) -> VecDeque<SocketAddr> {
+        let list = match peers {
+            Peers::Compact(buf) => return parse_peerlist(buf.as_slice(), false),
+            Peers::Dict(list) => list,
+        };
+
+        let mut res = VecDeque::new();
+        for p in list {
+            if let Ok(ip) = p.ip.parse::<IpAddr>() {
+                res.push_back(SocketAddr::new(ip, p.port));
+            } else if self.proxy.is_none() {
+                if let Ok(mut addrs) = tokio::net::lookup_host((p.ip.as_str(), p.port)).await {
+                    res.extend(addrs.next());
+                }
+            }
+        }
+        res
+    }
+
+    async fn get_peerlist(&mut self, event: Option<Event>) -> Result<(), TrackerError> {
+        let tracker = self.tracker.lock().await.clone();
+
         // manually encode bytes
-        let url = format!(
+        let mut url = format!(
             "{}?info_hash={}&peer_id={}",
             self.announce,
             serialize_bytes(&self.info_hash),
             serialize_bytes(&self.peer_id)
         );
-        let mut params = vec![("port", self.port as u64), ("compact", 1)];
+        if let Some(id) = &tracker.tracker_id {
+            url.push_str(&format!("&trackerid={}", serialize_bytes(id)));
+        }
+        let numwant = if event == Some(Event::Stopped) {
+            0
+        } else {
+            NUMWANT
+        };
+        let mut params = vec![
+            ("port", self.port as u64),
+            ("compact", 1),
+            ("numwant", numwant),
+        ];
 
         {
             let p = self.progress.lock().await;
@@ -99,25 +361,109 @@ impl Peerlist {
             ]);
         }
 
-        let client = reqwest::Client::new();
-        let res = client
+        let mut req = self
+            .client
             .get(url.as_str())
             .query(&params)
-            .send()
-            .await
-            .expect("Could not parse tracker response!")
-            .bytes()
-            .await
-            .expect("Could not parse tracker response!");
-
-        let res: TrackerResponse =
-            serde_bencode::de::from_bytes(&res).expect("Could not parse tracker response!");
-
-        self.list
-            .replace(parse_peerlist(res.peers.as_slice()))
-            .await;
-        // hack for own tracker
-        self.list.push(format!("localhost:{}", 4444).to_string()).await;
-        self.interval = res.interval;
+            .query(&[("key", &tracker.key)]);
+        if let Some(event) = event {
+            req = req.query(&[("event", event.as_str())]);
+        }
+        if let Some(ip) = self.ipv6 {
+            req = req.query(&[("ipv6", ip.to_string())]);
+        }
+
+        let res = req.send().await?.bytes().await?;
+        let res = parse_response(&res)?;
+
+        if let Some(msg) = &res.warning_message {
+            println!("Tracker warning: {}", msg);
+        }
+        if let Some(id) = res.tracker_id {
+            self.tracker.lock().await.tracker_id = Some(id);
+        }
+        if let Some(interval) = res.interval {
+            self.interval = Duration::from_secs(interval);
+        }
+        if let Some(min) = res.min_interval {
+            self.min_interval = Duration::from_secs(min);
+        }
+        if let (Some(complete), Some(incomplete)) = (res.complete, res.incomplete) {
+            let mut stats = self.stats.lock().await;
+            let swarm = stats.swarm.get_or_insert_with(Default::default);
+            swarm.complete = complete;
+            swarm.incomplete = incomplete;
+        }
+
+        let mut peers = self.resolve(res.peers).await;
+        if let Some(peers6) = res.peers6 {
+            peers.extend(parse_peerlist(peers6.as_slice(), true));
+        }
+        {
+            let filter = self.filter.lock().await;
+            peers.retain(|p| !filter.is_blocked(&p.ip()));
+        }
+
+        self.list.replace(peers).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_peerlist, parse_response, Peers, TrackerError};
+    use std::net::SocketAddr;
+
+    #[test]
+    fn test_parse_peerlist() {
+        let peers = parse_peerlist(&[10, 0, 0, 1, 0x1a, 0xe1, 10, 0], false);
+        assert_eq!(
+            Vec::from(peers),
+            vec!["10.0.0.1:6881".parse::<SocketAddr>().unwrap()]
+        );
+
+        let mut buf = vec![0; 15];
+        buf.extend(&[1, 0x1a, 0xe1]);
+        let peers = parse_peerlist(&buf, true);
+        assert_eq!(
+            Vec::from(peers),
+            vec!["[::1]:6881".parse::<SocketAddr>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let res = parse_response(
+            b"d8:completei5e10:incompletei3e8:intervali1800e12:min intervali60e\
+              5:peers6:\x0a\x00\x00\x01\x1a\xe110:tracker id3:abce",
+        )
+        .unwrap();
+        assert_eq!(res.interval, Some(1800));
+        assert_eq!(res.min_interval, Some(60));
+        assert_eq!(res.complete, Some(5));
+        assert_eq!(res.incomplete, Some(3));
+        assert_eq!(res.tracker_id.unwrap().as_slice(), b"abc");
+        assert!(matches!(res.peers, Peers::Compact(ref b) if b.len() == 6));
+
+        let res = parse_response(
+            b"d8:intervali900e5:peersld2:ip8:10.0.0.17:peer id20:aaaaaaaaaaaaaaaaaaaa\
+              4:porti6881eed2:ip3:::14:porti80eee15:warning message4:slowe",
+        )
+        .unwrap();
+        assert_eq!(res.warning_message.as_deref(), Some("slow"));
+        match res.peers {
+            Peers::Dict(list) => {
+                assert_eq!(list.len(), 2);
+                assert_eq!(list[0].ip, "10.0.0.1");
+                assert_eq!(list[0].port, 6881);
+                assert_eq!(list[1].ip, "::1");
+            }
+            _ => panic!("expected dictionary peers"),
+        }
+
+        match parse_response(b"d14:failure reason9:not founde") {
+            Err(TrackerError::Failure(reason)) => assert_eq!(reason, "not found"),
+            _ => panic!("expected failure"),
+        }
     }
 }
@@ -0,0 +1,392 @@
+use crate::utils::bitfield::Bitfield;
+use std::cmp::Reverse;
+use std::str::FromStr;
+
+// how much a file is wanted, in increasing order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Skip,
+    Low,
+    Normal,
+    High,
+}
+
+impl FromStr for Priority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Priority, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "skip" => Ok(Priority::Skip),
+            "low" => Ok(Priority::Low),
+            "normal" => Ok(Priority::Normal),
+            "high" => Ok(Priority::High),
+            _ => Err(format!("unknown priority {}", s)),
+        }
+    }
+}
+
+// the order the pieces of a file are picked in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Order {
+    Any,             // whatever order the work queue has
+    Sequential,      // in order from the cursor
+    Deadline(usize), // sequential, with the next n pieces before anything else
+}
+
+impl FromStr for Order {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Order, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "any" => Ok(Order::Any),
+            "sequential" => Ok(Order::Sequential),
+            s => s
+                .strip_prefix("deadline:")
+                .and_then(|n| n.parse().ok())
+                .filter(|n| *n > 0)
+                .map(Order::Deadline)
+                .ok_or_else(|| format!("unknown order {}", s)),
+        }
+    }
+}
+
+// what the picker sorts pieces by, highest first:
+// urgent pieces, then priority, then closeness to the cursor
+pub type Rank = (bool, Priority, Reverse<usize>);
+
+// priorities and orders of the files of a torrent and of the pieces they cover
+// a piece shared by several files takes the highest of their priorities,
+// so pieces spanning a skipped and a wanted file are still downloaded
+#[derive(Debug)]
+pub struct Priorities {
+    files: Vec<Priority>,
+    orders: Vec<Order>,
+    starts: Vec<usize>,                    // offset of each file in the torrent
+    spans: Vec<Option<(usize, usize)>>,    // first and last piece of each file
+    cursors: Vec<usize>,                   // piece of each file that is wanted next when streaming
+    windows: Vec<usize>,                   // end of the deadline window of each file
+    pieces: Vec<(Priority, Order, usize)>, // along with the file the order comes from
+    urgent: Vec<u32>,                      // readers waiting on each piece
+    have: Vec<bool>,                       // pieces that are verified
+    piece_length: usize,
+    paused: bool, // nothing is wanted while set
+}
+
+impl Priorities {
+    pub fn new(lengths: &[usize], piece_length: usize) -> Priorities {
+        let total: usize = lengths.iter().sum();
+        let num_pieces = total.div_ceil(piece_length);
+
+        let mut spans = Vec::with_capacity(lengths.len());
+        let mut starts = Vec::with_capacity(lengths.len());
+        let mut start = 0;
+        for len in lengths {
+            starts.push(start);
+            if *len == 0 {
+                spans.push(None);
+            } else {
+                spans.push(Some((
+                    start / piece_length,
+                    (start + len - 1) / piece_length,
+                )));
+            }
+            start += len;
+        }
+
+        let cursors: Vec<usize> = spans.iter().map(|s| s.map_or(0, |s| s.0)).collect();
+        let mut p = Priorities {
+            files: vec![Priority::Normal; lengths.len()],
+            orders: vec![Order::Any; lengths.len()],
+            starts,
+            spans,
+            windows: cursors.clone(),
+            cursors,
+            pieces: vec![(Priority::Normal, Order::Any, 0); num_pieces],
+            urgent: vec![0; num_pieces],
+            have: vec![false; num_pieces],
+            piece_length,
+            paused: false,
+        };
+        p.update();
+        p
+    }
+
+    pub fn file(&self, file: usize) -> Priority {
+        self.files[file]
+    }
+
+    // returns None if there is no such file
+    pub fn set_file(&mut self, file: usize, p: Priority) -> Option<()> {
+        *self.files.get_mut(file)? = p;
+        self.update();
+        Some(())
+    }
+
+    // returns None if there is no such file
+    pub fn set_order(&mut self, file: usize, o: Order) -> Option<()> {
+        *self.orders.get_mut(file)? = o;
+        self.update();
+        Some(())
+    }
+
+    // moves the cursor of file to the piece holding offset
+    // returns None if there is no such byte
+    pub fn seek(&mut self, file: usize, offset: usize) -> Option<()> {
+        let (first, last) = (*self.spans.get(file)?)?;
+        let idx = (self.starts[file] + offset) / self.piece_length;
+        if idx < first || last < idx {
+            return None;
+        }
+        self.cursors[file] = idx;
+        self.update_window(file);
+        Some(())
+    }
+
+    // marks a piece as verified, sliding deadline windows past it
+    pub fn add(&mut self, idx: usize) {
+        self.have[idx] = true;
+        self.update_windows();
+    }
+
+    // takes the verified pieces from bf, e.g. after a recheck
+    pub fn set_have(&mut self, bf: &Bitfield) {
+        for (i, x) in self.have.iter_mut().enumerate() {
+            *x = bf.has(i);
+        }
+        self.update_windows();
+    }
+
+    // makes a piece urgent until a matching unhurry
+    pub fn hurry(&mut self, idx: usize) {
+        self.urgent[idx] += 1;
+    }
+
+    pub fn unhurry(&mut self, idx: usize) {
+        self.urgent[idx] = self.urgent[idx].saturating_sub(1);
+    }
+
+    pub fn pause(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    // recomputes the pieces from the files overlapping them
+    // each piece takes the highest priority and most demanding order of its wanted files
+    fn update(&mut self) {
+        for x in self.pieces.iter_mut() {
+            *x = (Priority::Skip, Order::Any, usize::MAX);
+        }
+        // unless a wanted file asks for an order, a piece goes by its first file
+        for (f, span) in self.spans.iter().enumerate() {
+            if let Some((first, last)) = span {
+                for x in self.pieces[*first..=*last].iter_mut() {
+                    x.2 = x.2.min(f);
+                }
+            }
+        }
+        let files = self.files.iter().zip(&self.orders).zip(&self.spans);
+        for (f, ((p, o), span)) in files.enumerate() {
+            if let (Some((first, last)), true) = (span, *p != Priority::Skip) {
+                for x in self.pieces[*first..=*last].iter_mut() {
+                    if *o > x.1 {
+                        x.2 = f;
+                    }
+                    x.0 = x.0.max(*p);
+                    x.1 = x.1.max(*o);
+                }
+            }
+        }
+        self.update_windows();
+    }
+
+    fn update_windows(&mut self) {
+        for f in 0..self.files.len() {
+            self.update_window(f);
+        }
+    }
+
+    // a deadline window holds the next n missing pieces from the cursor,
+    // so it moves along as they arrive
+    fn update_window(&mut self, file: usize) {
+        let cursor = self.cursors[file];
+        let mut end = cursor;
+        if let (Order::Deadline(n), Some((_, last))) = (self.orders[file], self.spans[file]) {
+            let mut missing = 0;
+            while end <= last && missing < n {
+                if !self.have[end] {
+                    missing += 1;
+                }
+                end += 1;
+            }
+        }
+        self.windows[file] = end;
+    }
+
+    // how eagerly the picker should take a piece, None if it shouldn't
+    pub fn rank(&self, idx: u32) -> Option<Rank> {
+        let idx = idx as usize;
+        let (p, o, file) = *self.pieces.get(idx)?;
+        if p == Priority::Skip || self.paused {
+            return None;
+        }
+
+        // pieces of the file behind its cursor come last
+        let cursor = self.cursors[file];
+        let dist = match self.spans[file] {
+            Some((first, last)) if idx < cursor => last + 1 - cursor + idx - first,
+            _ => idx - cursor,
+        };
+        let in_window = cursor <= idx && idx < self.windows[file];
+        let urgent = self.urgent[idx] > 0 || (in_window && matches!(o, Order::Deadline(_)));
+        let dist = if o == Order::Any {
+            self.pieces.len()
+        } else {
+            dist
+        };
+        Some((urgent, p, Reverse(dist)))
+    }
+
+    // true if all the wanted pieces are in bf
+    pub fn complete(&self, bf: &Bitfield) -> bool {
+        self.pieces
+            .iter()
+            .enumerate()
+            .all(|(i, (p, _, _))| *p == Priority::Skip || bf.has(i))
+    }
+}
+
+// parses file indices like 0,3-5
+pub fn parse_indices(s: &str) -> Option<Vec<usize>> {
+    let mut res = Vec::new();
+    for part in s.split(',') {
+        match part.split_once('-') {
+            Some((a, b)) => {
+                let (a, b): (usize, usize) = (a.trim().parse().ok()?, b.trim().parse().ok()?);
+                if a > b {
+                    return None;
+                }
+                res.extend(a..=b);
+            }
+            None => res.push(part.trim().parse().ok()?),
+        }
+    }
+    Some(res)
+}
+
+// parses a setting for some files like 0,3-5=high
+pub fn parse_files<T: FromStr>(s: &str) -> Option<(Vec<usize>, T)> {
+    let (files, x) = s.split_once('=')?;
+    Some((parse_indices(files)?, x.trim().parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_files, parse_indices, Order, Priorities, Priority};
+    use crate::utils::bitfield::Bitfield;
+    use std::cmp::Reverse;
+
+    #[test]
+    fn test_piece_priorities() {
+        // pieces of 10: file 0 is 0-14, file 1 is 15-24, file 2 is empty, file 3 is 25-39
+        let mut p = Priorities::new(&[15, 10, 0, 15], 10);
+        let prio = |p: &Priorities, i| p.rank(i).map(|r| r.1);
+        assert_eq!(prio(&p, 3), Some(Priority::Normal));
+        assert_eq!(prio(&p, 4), None);
+
+        p.set_file(0, Priority::Skip).unwrap();
+        assert_eq!(prio(&p, 0), None);
+        // shared with file 1
+        assert_eq!(prio(&p, 1), Some(Priority::Normal));
+
+        p.set_file(1, Priority::Skip).unwrap();
+        p.set_file(3, Priority::High).unwrap();
+        assert_eq!(prio(&p, 1), None);
+        assert_eq!(prio(&p, 2), Some(Priority::High));
+        assert_eq!(prio(&p, 3), Some(Priority::High));
+        assert!(p.set_file(4, Priority::Low).is_none());
+
+        let mut bf = Bitfield::new(1);
+        bf.add(2);
+        assert!(!p.complete(&bf));
+        bf.add(3);
+        assert!(p.complete(&bf));
+
+        p.pause(true);
+        assert_eq!(prio(&p, 2), None);
+        assert!(p.complete(&bf));
+        p.pause(false);
+        assert_eq!(prio(&p, 2), Some(Priority::High));
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("HIGH".parse(), Ok(Priority::High));
+        assert!("urgent".parse::<Priority>().is_err());
+        assert_eq!(parse_indices("0,3-5, 9"), Some(vec![0, 3, 4, 5, 9]));
+        assert_eq!(parse_indices("2-1"), None);
+        assert_eq!(parse_indices("a"), None);
+        assert_eq!(parse_files("1-2=low"), Some((vec![1, 2], Priority::Low)));
+        assert_eq!(parse_files::<Priority>("1-2"), None);
+        assert_eq!("deadline:8".parse(), Ok(Order::Deadline(8)));
+        assert!("deadline:0".parse::<Order>().is_err());
+    }
+
+    #[test]
+    fn test_order() {
+        // pieces of 10: file 0 is pieces 0-4, file 1 is 5-9
+        let mut p = Priorities::new(&[50, 50], 10);
+        assert_eq!(p.rank(0), p.rank(9));
+
+        p.set_order(1, Order::Sequential).unwrap();
+        p.seek(1, 25).unwrap();
+        assert_eq!(p.rank(7), Some((false, Priority::Normal, Reverse(0))));
+        assert!(p.rank(8) < p.rank(7));
+        // wrapped around
+        assert!(p.rank(6) < p.rank(9));
+        assert!(p.rank(0) < p.rank(6));
+
+        p.set_order(0, Order::Deadline(2)).unwrap();
+        p.set_file(1, Priority::High).unwrap();
+        p.seek(0, 0).unwrap();
+        assert!(p.rank(1) > p.rank(5));
+        assert!(p.rank(0) > p.rank(1));
+        // outside the window
+        assert!(p.rank(2) < p.rank(5));
+
+        p.hurry(4);
+        assert!(p.rank(4) > p.rank(5));
+        assert!(p.rank(4) < p.rank(1));
+        p.unhurry(4);
+        assert!(p.rank(4) < p.rank(5));
+
+        assert!(p.seek(1, 50).is_none());
+        assert!(p.seek(2, 0).is_none());
+    }
+
+    #[test]
+    fn test_window_slides() {
+        // pieces of 10: file 0 is pieces 0-4, file 1 is 5-9
+        let mut p = Priorities::new(&[50, 50], 10);
+        p.set_order(0, Order::Deadline(2)).unwrap();
+        let urgent = |p: &Priorities, i| p.rank(i).unwrap().0;
+        assert!(urgent(&p, 0) && urgent(&p, 1) && !urgent(&p, 2));
+
+        // the window is the next two missing pieces
+        p.add(0);
+        assert!(urgent(&p, 1) && urgent(&p, 2) && !urgent(&p, 3));
+        p.add(2);
+        assert!(urgent(&p, 1) && urgent(&p, 3) && !urgent(&p, 4));
+
+        let mut bf = Bitfield::new(2);
+        bf.add(1);
+        p.set_have(&bf);
+        assert!(urgent(&p, 0) && urgent(&p, 2) && !urgent(&p, 3));
+
+        // each file streams from its own cursor
+        p.set_order(1, Order::Sequential).unwrap();
+        p.seek(1, 30).unwrap();
+        p.seek(0, 20).unwrap();
+        assert_eq!(p.rank(8), Some((false, Priority::Normal, Reverse(0))));
+        assert!(urgent(&p, 2) && urgent(&p, 3) && !urgent(&p, 0));
+        assert!(p.rank(9) > p.rank(5));
+    }
+}
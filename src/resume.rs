@@ -0,0 +1,142 @@
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+// size and modification time of a file, zero if it is missing
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct FileState {
+    pub length: u64,
+    pub mtime: u64, // seconds since the epoch
+}
+
+impl FileState {
+    pub fn of(path: &Path) -> FileState {
+        let meta = match fs::metadata(path) {
+            Ok(m) => m,
+            Err(_) => return FileState::default(),
+        };
+        let mtime = meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs());
+
+        FileState {
+            length: meta.len(),
+            mtime,
+        }
+    }
+}
+
+// what we tell the tracker to recognize us by
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TrackerState {
+    #[serde(default)]
+    pub trackers: Vec<String>, // the trackers this is for, announce first
+    pub key: String,
+    #[serde(
+        rename = "tracker id",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub tracker_id: Option<ByteBuf>,
+}
+
+impl TrackerState {
+    pub fn new(trackers: &[String]) -> TrackerState {
+        TrackerState {
+            trackers: trackers.to_vec(),
+            key: format!("{:08x}", rand::random::<u32>()),
+            tracker_id: None,
+        }
+    }
+}
+
+// a piece that was partway done, with a bitfield of the blocks written
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Unfinished {
+    pub piece: u32,
+    pub blocks: ByteBuf,
+}
+
+// fast-resume data, kept next to the download as <name>.resume
+// the bitfield is trusted as long as the files look untouched since it was saved
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Resume {
+    #[serde(rename = "info hash")]
+    pub info_hash: ByteBuf,
+    pub bitfield: ByteBuf,
+    pub files: Vec<FileState>,
+    pub part: FileState,
+    #[serde(rename = "part files")]
+    pub part_files: Vec<usize>, // files whose bytes are in the part file
+    pub unfinished: Vec<Unfinished>,
+    pub peers: Vec<String>,
+    pub tracker: TrackerState,
+    #[serde(default)]
+    pub allocation: String, // how the files were laid out, sparse, full or none
+}
+
+impl Resume {
+    // returns None if there is no readable resume file
+    pub fn load(path: &Path) -> Option<Resume> {
+        let buf = fs::read(path).ok()?;
+        serde_bencode::from_bytes(&buf).ok()
+    }
+
+    // replaces the file at path all at once, so a crash can't leave half of it
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let buf = serde_bencode::to_bytes(self).map_err(|e| io::Error::other(e.to_string()))?;
+        let tmp = path.with_extension("resume.tmp");
+        fs::write(&tmp, buf)?;
+        fs::rename(&tmp, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileState, Resume, TrackerState, Unfinished};
+    use serde_bytes::ByteBuf;
+
+    #[test]
+    fn test_save_load() {
+        let dir = std::env::temp_dir().join(format!("ntorrent_resume_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a.resume");
+
+        let mut tracker = TrackerState::new(&["http://127.0.0.1/announce".to_string()]);
+        let r = Resume {
+            info_hash: ByteBuf::from(vec![1; 20]),
+            bitfield: ByteBuf::from(vec![0b1010_0000]),
+            files: vec![FileState {
+                length: 10,
+                mtime: 1234,
+            }],
+            part: FileState::default(),
+            part_files: vec![0],
+            unfinished: vec![Unfinished {
+                piece: 1,
+                blocks: ByteBuf::from(vec![0b1000_0000]),
+            }],
+            peers: vec!["127.0.0.1:4444".to_string(), "[::1]:80".to_string()],
+            tracker: tracker.clone(),
+            allocation: "full".to_string(),
+        };
+        r.save(&path).unwrap();
+        assert_eq!(Resume::load(&path), Some(r.clone()));
+
+        tracker.tracker_id = Some(ByteBuf::from(b"abc".to_vec()));
+        let r = Resume { tracker, ..r };
+        r.save(&path).unwrap();
+        assert_eq!(Resume::load(&path), Some(r));
+
+        assert_eq!(FileState::of(&dir.join("missing")), FileState::default());
+        std::fs::write(&path, b"garbage").unwrap();
+        assert_eq!(Resume::load(&path), None);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,243 @@
+use serde::{Deserialize, Deserializer};
+use serde_bytes::ByteBuf;
+use std::collections::{HashMap, HashSet};
+
+// paths in torrents come from strangers, so before anything touches the disk
+// every component is made safe to create on any filesystem:
+// - invalid UTF-8 is replaced with U+FFFD
+// - "", "." and ".." are dropped, so nothing climbs out of the download
+// - separators, control characters and <>:"|?* become _
+// - trailing dots and spaces are trimmed, as windows would
+// - device names like CON or com1.txt get a leading _
+// - components are cut to MAX_COMPONENT bytes, keeping a short extension
+// - files whose paths differ only in case get a number, e.g. a_1.txt,
+//   and links that point through them are changed to match
+// the only thing rejected is a path with nothing left after this
+pub const MAX_COMPONENT: usize = 255;
+
+const RESERVED: [&str; 22] = [
+    "con", "prn", "aux", "nul", "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8",
+    "com9", "lpt1", "lpt2", "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9",
+];
+
+// decodes a bencoded string that may not be UTF-8
+pub fn lossy<'de, D: Deserializer<'de>>(d: D) -> Result<String, D::Error> {
+    let b = ByteBuf::deserialize(d)?;
+    Ok(String::from_utf8_lossy(&b).into_owned())
+}
+
+pub fn lossy_list<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<String>, D::Error> {
+    let l = Vec::<ByteBuf>::deserialize(d)?;
+    Ok(l.iter()
+        .map(|b| String::from_utf8_lossy(b).into_owned())
+        .collect())
+}
+
+// a single path component, None if it should be dropped
+pub fn component(s: &str) -> Option<String> {
+    let s: String = s
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let s = s.trim_end_matches(['.', ' ']);
+    if s.is_empty() {
+        return None;
+    }
+
+    let stem = s.split('.').next().unwrap().trim_end().to_lowercase();
+    let s = if RESERVED.contains(&stem.as_str()) {
+        format!("_{}", s)
+    } else {
+        s.to_string()
+    };
+    Some(truncate(s))
+}
+
+// cuts a component to MAX_COMPONENT bytes on a character boundary
+fn truncate(s: String) -> String {
+    if s.len() <= MAX_COMPONENT {
+        return s;
+    }
+
+    // keep the extension if it is short, so the file still opens with the right program
+    let ext = match s.rfind('.') {
+        Some(i) if s.len() - i <= 16 => &s[i..],
+        _ => "",
+    };
+    let mut end = MAX_COMPONENT - ext.len();
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &s[..end], ext)
+}
+
+// a whole path, None if nothing is left of it
+pub fn path(parts: &[String]) -> Option<Vec<String>> {
+    let res: Vec<String> = parts.iter().filter_map(|p| component(p)).collect();
+    if res.is_empty() {
+        return None;
+    }
+    Some(res)
+}
+
+// adds n to a name before its extension, cutting the name so it still fits
+fn numbered(name: &str, n: usize) -> String {
+    let (stem, ext) = match name.rfind('.') {
+        Some(i) if i > 0 && name.len() - i <= 16 => name.split_at(i),
+        _ => (name, ""),
+    };
+    let suffix = format!("_{}{}", n, ext);
+    let mut end = stem.len().min(MAX_COMPONENT - suffix.len());
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &stem[..end], suffix)
+}
+
+// keeps the files of a torrent from landing on each other
+// on filesystems that ignore case
+#[derive(Default)]
+pub struct Names {
+    files: HashSet<String>,
+    dirs: HashSet<String>,
+    given: HashMap<String, String>, // path as given -> the name its last part got first
+}
+
+impl Names {
+    // renames whatever part of path is already taken
+    // a directory clashes only with a file, a file with either
+    pub fn unique(&mut self, mut path: Vec<String>) -> Vec<String> {
+        let mut prefix = String::new();
+        let mut given = String::new();
+        let last = path.len() - 1;
+        for (i, part) in path.iter_mut().enumerate() {
+            given = format!("{}{}", given, part);
+            let taken = |p: &str, names: &Names| {
+                let key = format!("{}{}", prefix, p).to_lowercase();
+                names.files.contains(&key) || (i == last && names.dirs.contains(&key))
+            };
+
+            let mut n = 1;
+            let mut name = part.clone();
+            while taken(&name, self) {
+                name = numbered(part, n);
+                n += 1;
+            }
+            self.given
+                .entry(given.clone())
+                .or_insert_with(|| name.clone());
+            given.push('/');
+            *part = name;
+
+            prefix = format!("{}{}/", prefix, part).to_lowercase();
+            if i < last {
+                self.dirs.insert(prefix.trim_end_matches('/').to_string());
+            }
+        }
+        self.files.insert(prefix.trim_end_matches('/').to_string());
+        path
+    }
+
+    // gives a path within the torrent, such as a link target, the names unique gave it
+    pub fn resolve(&self, mut path: Vec<String>) -> Vec<String> {
+        let mut given = String::new();
+        for part in path.iter_mut() {
+            given = format!("{}{}", given, part);
+            if let Some(name) = self.given.get(&given) {
+                *part = name.clone();
+            }
+            given.push('/');
+        }
+        path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{component, path, Names, MAX_COMPONENT};
+
+    fn strings(v: &[&str]) -> Vec<String> {
+        v.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_component() {
+        assert_eq!(component("a.txt"), Some("a.txt".to_string()));
+        assert_eq!(component(".."), None);
+        assert_eq!(component("."), None);
+        assert_eq!(component(""), None);
+        assert_eq!(component(". . ."), None);
+        assert_eq!(component("/etc/passwd"), Some("_etc_passwd".to_string()));
+        assert_eq!(component("..\\..\\x"), Some(".._.._x".to_string()));
+        assert_eq!(component("C:"), Some("C_".to_string()));
+        assert_eq!(component("a\0b\n"), Some("a_b_".to_string()));
+        assert_eq!(component("notes.txt. "), Some("notes.txt".to_string()));
+        assert_eq!(component("CON"), Some("_CON".to_string()));
+        assert_eq!(component("com1.tar.gz"), Some("_com1.tar.gz".to_string()));
+        assert_eq!(component("console"), Some("console".to_string()));
+
+        let long = component(&format!("{}.mkv", "é".repeat(200))).unwrap();
+        assert!(long.len() <= MAX_COMPONENT);
+        assert!(long.ends_with("é.mkv"));
+        let long = component(&"x".repeat(300)).unwrap();
+        assert_eq!(long.len(), MAX_COMPONENT);
+    }
+
+    #[test]
+    fn test_path() {
+        assert_eq!(
+            path(&strings(&["..", "..", "etc", ".", "passwd"])),
+            Some(strings(&["etc", "passwd"]))
+        );
+        assert_eq!(path(&strings(&["..", ""])), None);
+        assert_eq!(path(&[]), None);
+    }
+
+    #[test]
+    fn test_names() {
+        let mut n = Names::default();
+        assert_eq!(n.unique(strings(&["a.txt"])), strings(&["a.txt"]));
+        assert_eq!(n.unique(strings(&["A.TXT"])), strings(&["A_1.TXT"]));
+        assert_eq!(n.unique(strings(&["a.txt"])), strings(&["a_2.txt"]));
+        // a file where a directory is and the other way around
+        assert_eq!(n.unique(strings(&["d", "x"])), strings(&["d", "x"]));
+        assert_eq!(n.unique(strings(&["D"])), strings(&["D_1"]));
+        assert_eq!(
+            n.unique(strings(&["a.txt", "y"])),
+            strings(&["a_3.txt", "y"])
+        );
+        assert_eq!(
+            n.unique(strings(&["a.txt", "z"])),
+            strings(&["a_3.txt", "z"])
+        );
+        assert_eq!(n.unique(strings(&["d", "X"])), strings(&["d", "X_1"]));
+
+        // numbered names still fit
+        let long = component(&format!("{}.txt", "y".repeat(300))).unwrap();
+        n.unique(vec![long.clone()]);
+        let renamed = n.unique(vec![long.to_uppercase()]);
+        assert_eq!(renamed[0].len(), MAX_COMPONENT);
+        assert!(renamed[0].ends_with("Y_1.TXT"));
+    }
+
+    #[test]
+    fn test_resolve() {
+        let mut n = Names::default();
+        n.unique(strings(&["a.txt"]));
+        n.unique(strings(&["A.TXT", "b"]));
+        n.unique(strings(&["a.txt"]));
+        // the first file given that name, and what was renamed on the way
+        assert_eq!(n.resolve(strings(&["a.txt"])), strings(&["a.txt"]));
+        assert_eq!(
+            n.resolve(strings(&["A.TXT", "b"])),
+            strings(&["A_1.TXT", "b"])
+        );
+        assert_eq!(n.resolve(strings(&["A.TXT"])), strings(&["A_1.TXT"]));
+        // not in the torrent
+        assert_eq!(n.resolve(strings(&["c", "d"])), strings(&["c", "d"]));
+    }
+}
@@ -0,0 +1,336 @@
+use crate::consts::{TIMEOUT, UDP_ATTEMPTS};
+use crate::peerlist::{tracker_client, TrackerError};
+use crate::proxy::{self, Proxy};
+use crate::torrents::Torrent;
+use crate::utils::serialize_bytes;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::net::SocketAddr;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::timeout;
+use url::Url;
+
+// magic constant identifying the UDP tracker protocol (BEP 15)
+const UDP_PROTOCOL_ID: u64 = 0x417_2710_1980;
+const ACTION_CONNECT: u32 = 0;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+// swarm numbers for one torrent as reported by a tracker
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct ScrapeInfo {
+    pub complete: u64,   // seeders
+    pub incomplete: u64, // leechers
+    pub downloaded: u64, // times the download was completed
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+struct ScrapeResponse {
+    #[serde(rename = "failure reason")]
+    pub failure_reason: Option<String>,
+    pub files: HashMap<ByteBuf, ScrapeInfo>,
+}
+
+// derives the scrape URL of an HTTP tracker (BEP 48)
+// only possible if the last path segment starts with "announce"
+pub fn scrape_url(announce: &str) -> Option<String> {
+    let i = announce.rfind('/')?;
+    let (base, last) = announce.split_at(i + 1);
+    if !last.starts_with("announce") {
+        return None;
+    }
+    Some(format!("{}scrape{}", base, &last["announce".len()..]))
+}
+
+fn parse_response(buf: &[u8], info_hash: &[u8]) -> Result<ScrapeInfo, TrackerError> {
+    let res: ScrapeResponse = serde_bencode::de::from_bytes(buf)?;
+    if let Some(reason) = res.failure_reason {
+        return Err(TrackerError::Failure(reason));
+    }
+    res.files
+        .get(&ByteBuf::from(info_hash.to_vec()))
+        .copied()
+        .ok_or_else(|| TrackerError::Other("torrent not tracked".to_string()))
+}
+
+// asks a tracker about a torrent's swarm
+pub async fn scrape(
+    tracker: &str,
+    info_hash: &[u8],
+    client: &reqwest::Client,
+    proxy: Option<&Proxy>,
+) -> Result<ScrapeInfo, TrackerError> {
+    if tracker.starts_with("udp://") {
+        return scrape_udp(tracker, info_hash, proxy).await;
+    }
+
+    let url = scrape_url(tracker)
+        .ok_or_else(|| TrackerError::Other("tracker doesn't support scrape".to_string()))?;
+    let sep = if url.contains('?') { '&' } else { '?' };
+    let url = format!(
+        "{}{}info_hash={}",
+        url,
+        sep,
+        serialize_bytes(&info_hash.to_vec())
+    );
+
+    let res = client.get(url.as_str()).send().await?.bytes().await?;
+    parse_response(&res, info_hash)
+}
+
+// scrapes every tracker of a torrent and prints what they report
+pub async fn print_scrape(torrent: &Torrent, proxy: Option<&Proxy>) {
+    let client = tracker_client(proxy);
+    for tracker in torrent.trackers.iter() {
+        match scrape(tracker, &torrent.info_hash, &client, proxy).await {
+            Ok(s) => println!(
+                "{} --- {} seeders, {} leechers, {} completed",
+                tracker, s.complete, s.incomplete, s.downloaded
+            ),
+            Err(e) => println!("{} --- {}", tracker, e),
+        }
+    }
+}
+
+// a UDP socket talking to one tracker, through a SOCKS5 relay if proxied
+struct UdpTracker {
+    socket: UdpSocket,
+    host: String,
+    port: u16,
+    relay: Option<(TcpStream, SocketAddr)>,
+}
+
+impl UdpTracker {
+    async fn new(tracker: &str, proxy: Option<&Proxy>) -> Result<UdpTracker, TrackerError> {
+        let url = Url::parse(tracker).map_err(|_| TrackerError::Other("bad URL".to_string()))?;
+        let host = url
+            .host_str()
+            .ok_or_else(|| TrackerError::Other("missing host".to_string()))?
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string();
+        let port = url
+            .port()
+            .ok_or_else(|| TrackerError::Other("missing port".to_string()))?;
+
+        let (socket, relay) = match proxy {
+            Some(p) => {
+                let (control, relay) = p.udp_associate().await?;
+                let local = if relay.is_ipv4() {
+                    "0.0.0.0:0"
+                } else {
+                    "[::]:0"
+                };
+                (UdpSocket::bind(local).await?, Some((control, relay)))
+            }
+            None => {
+                let mut socket = None;
+                for addr in tokio::net::lookup_host((host.as_str(), port)).await? {
+                    let local = if addr.is_ipv4() {
+                        "0.0.0.0:0"
+                    } else {
+                        "[::]:0"
+                    };
+                    let s = UdpSocket::bind(local).await?;
+                    if s.connect(addr).await.is_ok() {
+                        socket = Some(s);
+                        break;
+                    }
+                }
+                let socket = socket
+                    .ok_or_else(|| TrackerError::Other("could not resolve tracker".to_string()))?;
+                (socket, None)
+            }
+        };
+
+        Ok(UdpTracker {
+            socket,
+            host,
+            port,
+            relay,
+        })
+    }
+
+    async fn send(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        match &self.relay {
+            Some((_, relay)) => {
+                let mut packet = proxy::udp_header(&self.host, self.port)?;
+                packet.extend(buf);
+                self.socket.send_to(&packet, *relay).await?;
+            }
+            None => {
+                self.socket.send(buf).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> std::io::Result<Vec<u8>> {
+        let mut buf = vec![0; 2048];
+        let n = self.socket.recv(&mut buf).await?;
+        buf.truncate(n);
+
+        if self.relay.is_some() {
+            return proxy::strip_udp_header(&buf)
+                .map(|b| b.to_vec())
+                .ok_or_else(|| std::io::Error::other("bad datagram from proxy"));
+        }
+        Ok(buf)
+    }
+
+    // sends a request and waits for the response with a matching transaction id
+    // retries on timeouts since datagrams can be lost
+    async fn request(&mut self, action: u32, buf: &[u8]) -> Result<Vec<u8>, TrackerError> {
+        let txid: u32 = rand::random();
+        let mut packet = buf.to_vec();
+        packet[8..12].copy_from_slice(&action.to_be_bytes());
+        packet[12..16].copy_from_slice(&txid.to_be_bytes());
+
+        for _ in 0..UDP_ATTEMPTS {
+            self.send(&packet).await?;
+
+            let res = match timeout(TIMEOUT, self.recv()).await {
+                Ok(res) => res?,
+                Err(_) => continue,
+            };
+            if res.len() < 8 {
+                continue;
+            }
+
+            let mut cx = Cursor::new(&res);
+            let res_action = cx.read_u32::<BigEndian>()?;
+            if cx.read_u32::<BigEndian>()? != txid {
+                continue;
+            }
+            if res_action == ACTION_ERROR {
+                let msg = String::from_utf8_lossy(&res[8..]).to_string();
+                return Err(TrackerError::Failure(msg));
+            }
+            if res_action != action {
+                continue;
+            }
+            return Ok(res[8..].to_vec());
+        }
+
+        Err(TrackerError::Other("tracker did not respond".to_string()))
+    }
+
+    async fn connect(&mut self) -> Result<u64, TrackerError> {
+        let mut buf = Vec::new();
+        buf.write_u64::<BigEndian>(UDP_PROTOCOL_ID)?;
+        buf.extend(&[0; 8]); // action * transaction id
+        let res = self.request(ACTION_CONNECT, &buf).await?;
+
+        Ok(Cursor::new(res).read_u64::<BigEndian>()?)
+    }
+}
+
+// scrapes a UDP tracker (BEP 15)
+async fn scrape_udp(
+    tracker: &str,
+    info_hash: &[u8],
+    proxy: Option<&Proxy>,
+) -> Result<ScrapeInfo, TrackerError> {
+    let mut t = UdpTracker::new(tracker, proxy).await?;
+    let conn = t.connect().await?;
+
+    let mut buf = Vec::new();
+    buf.write_u64::<BigEndian>(conn)?;
+    buf.extend(&[0; 8]); // action * transaction id
+    buf.extend(info_hash);
+    let res = t.request(ACTION_SCRAPE, &buf).await?;
+
+    let mut cx = Cursor::new(res);
+    let complete = cx.read_u32::<BigEndian>()? as u64;
+    let downloaded = cx.read_u32::<BigEndian>()? as u64;
+    let incomplete = cx.read_u32::<BigEndian>()? as u64;
+    Ok(ScrapeInfo {
+        complete,
+        incomplete,
+        downloaded,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_response, scrape, scrape_url, ScrapeInfo};
+    use tokio::net::UdpSocket;
+
+    #[test]
+    fn test_scrape_url() {
+        assert_eq!(
+            scrape_url("http://t.example/announce").as_deref(),
+            Some("http://t.example/scrape")
+        );
+        assert_eq!(
+            scrape_url("http://t.example/x/announce.php?k=1").as_deref(),
+            Some("http://t.example/x/scrape.php?k=1")
+        );
+        assert_eq!(scrape_url("http://t.example/a"), None);
+        assert_eq!(scrape_url("http://t.example/announce/x"), None);
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let res = parse_response(
+            b"d5:filesd20:aaaaaaaaaaaaaaaaaaaad8:completei5e10:downloadedi50e10:incompletei10eeee",
+            b"aaaaaaaaaaaaaaaaaaaa",
+        )
+        .unwrap();
+        assert_eq!(
+            res,
+            ScrapeInfo {
+                complete: 5,
+                incomplete: 10,
+                downloaded: 50
+            }
+        );
+        assert!(parse_response(b"d5:filesdee", b"aaaaaaaaaaaaaaaaaaaa").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_scrape_udp() {
+        let mut server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = server.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            let mut buf = [0; 1024];
+
+            let (n, from) = server.recv_from(&mut buf).await.unwrap();
+            assert_eq!(n, 16);
+            assert_eq!(&buf[..8], &0x417_2710_1980u64.to_be_bytes());
+            let mut res = vec![0, 0, 0, 0];
+            res.extend(&buf[12..16]);
+            res.extend(&7u64.to_be_bytes());
+            server.send_to(&res, &from).await.unwrap();
+
+            let (n, from) = server.recv_from(&mut buf).await.unwrap();
+            assert_eq!(n, 36);
+            assert_eq!(&buf[..8], &7u64.to_be_bytes());
+            assert_eq!(&buf[16..36], &[1; 20]);
+            let mut res = vec![0, 0, 0, 2];
+            res.extend(&buf[12..16]);
+            for x in &[3u32, 40, 8] {
+                res.extend(&x.to_be_bytes());
+            }
+            server.send_to(&res, &from).await.unwrap();
+        });
+
+        let client = reqwest::Client::new();
+        let tracker = format!("udp://127.0.0.1:{}", port);
+        let res = scrape(&tracker, &[1; 20], &client, None).await.unwrap();
+        assert_eq!(
+            res,
+            ScrapeInfo {
+                complete: 3,
+                incomplete: 8,
+                downloaded: 40
+            }
+        );
+    }
+}
@@ -0,0 +1,145 @@
+use crate::downloading::Downloading;
+use sha1::{Digest, Sha1};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+
+fn hash(buf: &[u8]) -> [u8; 20] {
+    let mut hash = Sha1::new();
+    hash.input(buf);
+
+    let mut res = [0; 20];
+    res.copy_from_slice(hash.result().as_slice());
+    res
+}
+
+// who sent a block * hash of the block
+type Record = Option<(IpAddr, [u8; 20])>;
+
+// keeps track of who sent the blocks of pieces that failed verification
+// once such a piece is downloaded correctly, the blocks are compared
+// to find the peers that sent bad data, and those peers are banned
+#[derive(Debug, Default)]
+pub struct SmartBan {
+    failed: HashMap<u32, Vec<Record>>, // piece -> blocks
+    failures: HashMap<IpAddr, u32>,    // failed pieces per peer
+    banned: HashSet<IpAddr>,
+}
+
+impl SmartBan {
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        self.banned.contains(ip)
+    }
+
+    pub fn failures(&self, ip: &IpAddr) -> u32 {
+        *self.failures.get(ip).unwrap_or(&0)
+    }
+
+    // records the blocks of a piece that failed verification
+    // returns the peer banned if it sent the whole piece
+    pub fn piece_failed(&mut self, d: &Downloading) -> Option<IpAddr> {
+        let blocks: Vec<Record> = d
+            .blocks()
+            .map(|(buf, ip)| ip.map(|ip| (ip, hash(buf))))
+            .collect();
+
+        let peers: HashSet<IpAddr> = blocks.iter().flatten().map(|(ip, _)| *ip).collect();
+        for ip in peers.iter() {
+            *self.failures.entry(*ip).or_insert(0) += 1;
+        }
+        // blocks from web seeds or the resume file could be the bad ones
+        let single = peers.len() == 1 && blocks.iter().all(|b| b.is_some());
+
+        // merge with an earlier failure of the same piece
+        let record = self.failed.entry(d.piece.1).or_default();
+        if record.len() != blocks.len() {
+            *record = blocks;
+        } else {
+            for (old, new) in record.iter_mut().zip(blocks) {
+                if new.is_some() {
+                    *old = new;
+                }
+            }
+        }
+
+        // no need to compare when a single peer sent everything
+        if single {
+            let ip = *peers.iter().next()?;
+            self.banned.insert(ip);
+            return Some(ip);
+        }
+        None
+    }
+
+    // compares a verified piece against the failed copy of it
+    // returns the peers that are newly banned
+    pub fn piece_passed(&mut self, d: &Downloading) -> Vec<IpAddr> {
+        let record = match self.failed.remove(&d.piece.1) {
+            Some(r) => r,
+            None => return Vec::new(),
+        };
+
+        let mut res = Vec::new();
+        for (old, (buf, _)) in record.into_iter().zip(d.blocks()) {
+            if let Some((ip, h)) = old {
+                if h != hash(buf) && self.banned.insert(ip) {
+                    res.push(ip);
+                }
+            }
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SmartBan;
+    use crate::consts::BLOCKSIZE;
+    use crate::downloading::Downloading;
+    use crate::torrents::Piece;
+    use std::net::IpAddr;
+
+    fn download(blocks: &[(u8, &str)]) -> Downloading {
+        let mut d = Downloading::new(Piece([0; 20], 7, BLOCKSIZE * blocks.len() as u32));
+        for (b, ip) in blocks {
+            let (s, _) = d.next_request().unwrap();
+            let ip: IpAddr = ip.parse().unwrap();
+            d.receive(s, &vec![*b; BLOCKSIZE as usize], Some(ip));
+        }
+        d
+    }
+
+    #[test]
+    fn test_smart_ban() {
+        let mut sb = SmartBan::default();
+        let good: IpAddr = "10.0.0.1".parse().unwrap();
+        let bad: IpAddr = "10.0.0.2".parse().unwrap();
+
+        let failed = download(&[(1, "10.0.0.1"), (6, "10.0.0.2"), (3, "10.0.0.1")]);
+        assert_eq!(sb.piece_failed(&failed), None);
+        assert_eq!(sb.failures(&good), 1);
+        assert_eq!(sb.failures(&bad), 1);
+
+        let passed = download(&[(1, "10.0.0.3"), (2, "10.0.0.3"), (3, "10.0.0.3")]);
+        assert_eq!(sb.piece_passed(&passed), vec![bad]);
+        assert!(sb.is_banned(&bad));
+        assert!(!sb.is_banned(&good));
+    }
+
+    #[test]
+    fn test_single_source() {
+        let mut sb = SmartBan::default();
+        let failed = download(&[(1, "10.0.0.2"), (6, "10.0.0.2")]);
+        assert_eq!(sb.piece_failed(&failed), "10.0.0.2".parse().ok());
+
+        // a block of unknown origin may have been the bad one
+        let mut failed = Downloading::new(Piece([0; 20], 8, 2 * BLOCKSIZE));
+        let ip: IpAddr = "10.0.0.3".parse().unwrap();
+        for (i, from) in [Some(ip), None].iter().enumerate() {
+            let (s, _) = failed.next_request().unwrap();
+            failed.receive(s, &vec![i as u8; BLOCKSIZE as usize], *from);
+        }
+        assert_eq!(sb.piece_failed(&failed), None);
+        assert!(!sb.is_banned(&ip));
+        assert_eq!(sb.failures(&ip), 1);
+    }
+}
@@ -0,0 +1,60 @@
+use crate::disk::DiskStats;
+use crate::scrape::ScrapeInfo;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+// numbers reported by a worker about its current connection
+#[derive(Debug, Default, Clone)]
+pub struct PeerStats {
+    pub addr: Option<SocketAddr>,
+    pub rate: f64, // bytes per second
+    pub rtt: Option<Duration>,
+    pub queue_depth: u32,
+    pub outstanding: u32, // bytes requested but not yet received
+    pub snubbed: bool,
+}
+
+// runtime statistics shared between the client and workers
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub peers: HashMap<u64, PeerStats>, // keyed by worker id
+    pub swarm: Option<ScrapeInfo>,      // as reported by the tracker
+    pub disk: DiskStats,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(s) = self.swarm {
+            writeln!(
+                f,
+                "Swarm --- {} seeders, {} leechers, {} completed",
+                s.complete, s.incomplete, s.downloaded
+            )?;
+        }
+
+        writeln!(f, "{}", self.disk)?;
+
+        let mut ids: Vec<&u64> = self.peers.keys().collect();
+        ids.sort();
+
+        for id in ids {
+            let p = &self.peers[id];
+            let addr = p.addr.map_or("?".to_string(), |a| a.to_string());
+            let rtt = p.rtt.map_or(0, |d| d.as_millis());
+            writeln!(
+                f,
+                "Worker {} {} --- {:.1} KiB/s, rtt {}ms, queue {}, {} bytes outstanding{}",
+                id,
+                addr,
+                p.rate / 1024.0,
+                rtt,
+                p.queue_depth,
+                p.outstanding,
+                if p.snubbed { ", snubbed" } else { "" }
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,816 @@
+use crate::consts::FLUSH_SIZE;
+use crate::resume::FileState;
+use crate::torrents::Torrent;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+// how files are laid out on disk before their pieces arrive
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Allocation {
+    Sparse, // full size up front, taking space only as pieces are written
+    Full,   // all the space reserved up front
+    None,   // files grow as pieces are written
+}
+
+impl FromStr for Allocation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Allocation, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "sparse" => Ok(Allocation::Sparse),
+            "full" => Ok(Allocation::Full),
+            "none" => Ok(Allocation::None),
+            _ => Err(format!("unknown allocation mode {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Allocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Allocation::Sparse => "sparse",
+            Allocation::Full => "full",
+            Allocation::None => "none",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+// a disk operation that failed and the file it failed on
+#[derive(Debug)]
+pub struct DiskError {
+    pub op: &'static str,
+    pub path: PathBuf,
+    pub err: io::Error,
+}
+
+impl DiskError {
+    pub fn new(op: &'static str, path: &Path, err: io::Error) -> DiskError {
+        DiskError {
+            op,
+            path: path.to_path_buf(),
+            err,
+        }
+    }
+}
+
+impl fmt::Display for DiskError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "could not {} {}: {}",
+            self.op,
+            self.path.display(),
+            self.err
+        )
+    }
+}
+
+impl From<DiskError> for io::Error {
+    fn from(e: DiskError) -> io::Error {
+        io::Error::new(e.err.kind(), e.to_string())
+    }
+}
+
+// reserves len bytes for a file without changing what is in it
+#[cfg(target_os = "linux")]
+fn reserve(f: &File, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let res = unsafe { libc::fallocate(f.as_raw_fd(), 0, 0, len as libc::off_t) };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// elsewhere files can only be made sparse
+#[cfg(not(target_os = "linux"))]
+fn reserve(f: &File, len: u64) -> io::Result<()> {
+    if f.metadata()?.len() < len {
+        f.set_len(len)?;
+    }
+    Ok(())
+}
+
+// bytes of a file actually on disk, less than its length if it is sparse
+fn allocated(path: &Path) -> u64 {
+    match fs::metadata(path) {
+        #[cfg(unix)]
+        Ok(m) => std::os::unix::fs::MetadataExt::blocks(&m) * 512,
+        #[cfg(not(unix))]
+        Ok(m) => m.len(),
+        Err(_) => 0,
+    }
+}
+
+// bytes free for us on the filesystem holding path, None if unknown
+#[cfg(target_os = "linux")]
+pub fn free_space(path: &Path) -> Option<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    // the closest directory that already exists
+    let dir = path
+        .ancestors()
+        .find(|p| p.is_dir())
+        .unwrap_or_else(|| Path::new("."));
+    let dir = CString::new(dir.as_os_str().as_bytes()).ok()?;
+
+    let mut st: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(dir.as_ptr(), &mut st) } != 0 {
+        return None;
+    }
+    Some(st.f_bavail * st.f_frsize)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn free_space(_: &Path) -> Option<u64> {
+    None
+}
+
+// splits the bytes from start to end of the torrent across its files
+// returns file index * offset in file * length
+pub fn file_ranges(lengths: &[usize], start: usize, end: usize) -> Vec<(usize, usize, usize)> {
+    let mut res = Vec::new();
+    let mut file_start = 0;
+
+    for (i, len) in lengths.iter().enumerate() {
+        let file_end = file_start + len;
+        if *len > 0 && file_end > start && file_start < end {
+            let s = start.max(file_start);
+            let e = end.min(file_end);
+            res.push((i, s - file_start, e - s));
+        }
+        file_start = file_end;
+    }
+    res
+}
+
+fn write_at(path: &Path, offset: usize, data: &[u8]) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut f = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    f.seek(SeekFrom::Start(offset as u64))?;
+    f.write_all(data)
+}
+
+fn allocate_file(path: &Path, len: u64, mode: Allocation) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let f = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    match mode {
+        Allocation::Full => reserve(&f, len),
+        _ if f.metadata()?.len() < len => f.set_len(len),
+        _ => Ok(()),
+    }
+}
+
+// adds execute permission wherever there is read permission
+#[cfg(unix)]
+fn set_executable(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut perms = fs::metadata(path)?.permissions();
+    let mode = perms.mode();
+    perms.set_mode(mode | (mode & 0o444) >> 2);
+    fs::set_permissions(path, perms)
+}
+
+#[cfg(not(unix))]
+fn set_executable(_: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, path: &Path) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    std::os::unix::fs::symlink(target, path)
+}
+
+// links need extra privileges on windows, so they are left out
+#[cfg(not(unix))]
+fn symlink(_: &Path, _: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+fn read_at(path: &Path, offset: usize, buf: &mut [u8]) -> io::Result<()> {
+    let mut f = File::open(path)?;
+    f.seek(SeekFrom::Start(offset as u64))?;
+    f.read_exact(buf)
+}
+
+// moves a file or directory, copying it over if it is on another filesystem
+// either way it shows up at to all at once
+pub fn move_path(from: &Path, to: &Path) -> io::Result<()> {
+    if to.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists", to.display()),
+        ));
+    }
+    if let Some(dir) = to.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => copy_then_rename(from, to),
+        res => res,
+    }
+}
+
+// copies from next to to before renaming it into place
+fn copy_then_rename(from: &Path, to: &Path) -> io::Result<()> {
+    let tmp = moving_path(to);
+    // left over from a copy that was cut short
+    remove_tree(&tmp).ok();
+
+    copy_tree(from, &tmp, None)?;
+    fs::rename(&tmp, to)?;
+    remove_tree(from)
+}
+
+// copies from next to to ahead of moving it to another filesystem, while it
+// is still in use, so only what changes in the meantime is left for finish_copy
+// returns when the copy started, or None if from can just be renamed
+pub fn copy_ahead(from: &Path, to: &Path) -> io::Result<Option<SystemTime>> {
+    if !from.exists() || same_filesystem(from, to)? {
+        return Ok(None);
+    }
+    if let Some(dir) = to.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let since = SystemTime::now();
+    let tmp = moving_path(to);
+    remove_tree(&tmp).ok();
+    copy_tree(from, &tmp, None)?;
+    Ok(Some(since))
+}
+
+// catches the copy up with what changed since copy_ahead and renames it into place
+fn finish_copy(from: &Path, to: &Path, since: SystemTime) -> io::Result<()> {
+    let tmp = moving_path(to);
+    copy_tree(from, &tmp, Some(since))?;
+    fs::rename(&tmp, to)?;
+    remove_tree(from)
+}
+
+fn moving_path(to: &Path) -> PathBuf {
+    let name = to.file_name().unwrap_or_default().to_string_lossy();
+    to.with_file_name(format!(".{}.moving", name))
+}
+
+// whether from and the closest existing directory of to are on the same filesystem
+#[cfg(unix)]
+fn same_filesystem(from: &Path, to: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = to
+        .ancestors()
+        .find(|p| p.is_dir())
+        .unwrap_or_else(|| Path::new("."));
+    Ok(fs::symlink_metadata(from)?.dev() == fs::metadata(dir)?.dev())
+}
+
+// renaming is tried first anyway
+#[cfg(not(unix))]
+fn same_filesystem(_: &Path, _: &Path) -> io::Result<bool> {
+    Ok(true)
+}
+
+// copies links as links rather than what they point to
+// with since, only what is missing from to or changed after since is copied
+fn copy_tree(from: &Path, to: &Path, since: Option<SystemTime>) -> io::Result<()> {
+    let meta = fs::symlink_metadata(from)?;
+    let copied = fs::symlink_metadata(to).is_ok();
+    if meta.is_dir() {
+        if !copied {
+            fs::create_dir(to)?;
+        }
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_tree(&entry.path(), &to.join(entry.file_name()), since)?;
+        }
+        return Ok(());
+    }
+
+    // files are stamped by a clock that lags a little behind
+    let changed = match (since, meta.modified()) {
+        (Some(since), Ok(m)) => m + Duration::from_secs(1) >= since,
+        _ => true,
+    };
+    if copied {
+        if !changed {
+            return Ok(());
+        }
+        remove_tree(to)?;
+    }
+
+    if meta.file_type().is_symlink() {
+        symlink(&fs::read_link(from)?, to)
+    } else {
+        fs::copy(from, to)?;
+        // resume data only trusts files that look untouched
+        File::options()
+            .write(true)
+            .open(to)?
+            .set_modified(meta.modified()?)
+    }
+}
+
+fn remove_tree(path: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+// where the bytes of a torrent live on disk
+// pieces are written in place in their files, except that the bytes of
+// skipped files go to a sparse part file at their offset in the torrent,
+// so pieces shared with wanted files can still be served and checked
+// padding is all zeros and never touches the disk
+#[derive(Clone)]
+pub struct Storage {
+    paths: Vec<PathBuf>,
+    lengths: Vec<usize>,
+    starts: Vec<usize>, // offset of each file in the torrent
+    in_part: Vec<bool>,
+    padding: Vec<bool>,
+    executable: Vec<bool>,
+    links: Vec<Option<PathBuf>>, // target of each symlink
+    part: PathBuf,
+}
+
+impl Storage {
+    pub fn new(torrent: &Torrent, part: PathBuf) -> Storage {
+        let files = torrent
+            .files
+            .iter()
+            .map(|f| (PathBuf::from(f.path.join("/")), f.length))
+            .collect();
+        let mut s = Storage::from_files(files, part);
+
+        for (i, f) in torrent.files.iter().enumerate() {
+            // a link has no bytes of its own
+            s.padding[i] = f.is_padding() || f.is_symlink();
+            s.executable[i] = f.is_executable();
+            if f.is_symlink() {
+                s.links[i] = f.symlink_path.as_ref().map(|p| PathBuf::from(p.join("/")));
+            }
+        }
+        s
+    }
+
+    // files given as path * length
+    pub fn from_files(files: Vec<(PathBuf, usize)>, part: PathBuf) -> Storage {
+        let mut starts = Vec::new();
+        let mut start = 0;
+        for (_, len) in files.iter() {
+            starts.push(start);
+            start += len;
+        }
+
+        Storage {
+            in_part: vec![false; files.len()],
+            padding: vec![false; files.len()],
+            executable: vec![false; files.len()],
+            links: vec![None; files.len()],
+            lengths: files.iter().map(|f| f.1).collect(),
+            paths: files.into_iter().map(|f| f.0).collect(),
+            starts,
+            part,
+        }
+    }
+
+    pub fn path(&self, file: usize) -> &Path {
+        &self.paths[file]
+    }
+
+    // files whose bytes are kept in the part file
+    pub fn part_files(&self) -> Vec<usize> {
+        (0..self.paths.len()).filter(|i| self.in_part[*i]).collect()
+    }
+
+    pub fn set_part_files(&mut self, files: &[usize]) {
+        for (i, x) in self.in_part.iter_mut().enumerate() {
+            *x = files.contains(&i) && !self.padding[i];
+        }
+    }
+
+    // points whatever is under from at the same place under to, without moving anything
+    pub fn rebase(&mut self, from: &Path, to: &Path) {
+        for p in self.paths.iter_mut().chain(std::iter::once(&mut self.part)) {
+            // joining nothing would add a trailing slash
+            match p.strip_prefix(from) {
+                Ok(rest) if rest.as_os_str().is_empty() => *p = to.to_path_buf(),
+                Ok(rest) => *p = to.join(rest),
+                Err(_) => (),
+            }
+        }
+    }
+
+    // the files and directories directly under from that hold the torrent,
+    // along with where they go under to
+    // the part file goes first, so the files show up last
+    pub fn moves(&self, from: &Path, to: &Path) -> io::Result<Vec<(PathBuf, PathBuf)>> {
+        let mut roots: Vec<PathBuf> = Vec::new();
+        for p in std::iter::once(&self.part).chain(self.paths.iter()) {
+            let root = match p
+                .strip_prefix(from)
+                .ok()
+                .and_then(|r| r.components().next())
+            {
+                Some(c) => from.join(c),
+                None => continue,
+            };
+            if !roots.contains(&root) {
+                roots.push(root);
+            }
+        }
+
+        let moves: Vec<(PathBuf, PathBuf)> = roots
+            .into_iter()
+            .map(|r| (r.clone(), to.join(r.strip_prefix(from).unwrap())))
+            .collect();
+        // nothing is moved unless everything can be
+        if let Some((_, dest)) = moves.iter().find(|(_, dest)| dest.exists()) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", dest.display()),
+            ));
+        }
+        Ok(moves)
+    }
+
+    // moves what has been written to where moves says it goes,
+    // finishing the ones copied ahead since the time given
+    pub fn finish_moves(
+        &mut self,
+        moves: &[(PathBuf, PathBuf)],
+        copied: &[Option<SystemTime>],
+    ) -> io::Result<()> {
+        for ((root, dest), since) in moves.iter().zip(copied) {
+            match since {
+                Some(since) => finish_copy(root, dest, *since)?,
+                None if root.exists() => move_path(root, dest)?,
+                None => (),
+            }
+            self.rebase(root, dest);
+        }
+        Ok(())
+    }
+
+    // files holding some of the len bytes from start of the torrent
+    pub fn files_in(&self, start: usize, len: usize) -> Vec<usize> {
+        file_ranges(&self.lengths, start, start + len)
+            .into_iter()
+            .map(|x| x.0)
+            .filter(|i| !self.padding[*i])
+            .collect()
+    }
+
+    // writes data at start of the torrent
+    pub fn write(&self, start: usize, data: &[u8]) -> Result<(), DiskError> {
+        let mut i = 0;
+        for (file, offset, len) in file_ranges(&self.lengths, start, start + data.len()) {
+            if self.padding[file] {
+                i += len;
+                continue;
+            }
+            let (path, offset) = if self.in_part[file] {
+                (&self.part, self.starts[file] + offset)
+            } else {
+                (&self.paths[file], offset)
+            };
+            write_at(path, offset, &data[i..i + len])
+                .map_err(|e| DiskError::new("write", path, e))?;
+            i += len;
+        }
+        Ok(())
+    }
+
+    // reads len bytes from start of the torrent
+    pub fn read(&self, start: usize, len: usize) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0; len];
+        let mut i = 0;
+        for (file, offset, n) in file_ranges(&self.lengths, start, start + len) {
+            if self.padding[file] {
+                // left as zeros
+            } else if self.in_part[file] {
+                read_at(&self.part, self.starts[file] + offset, &mut buf[i..i + n])?;
+            } else {
+                read_at(&self.paths[file], offset, &mut buf[i..i + n])?;
+            }
+            i += n;
+        }
+        Ok(buf)
+    }
+
+    // moves a file's bytes out of the part file to where they belong
+    pub fn unpart(&mut self, file: usize) -> io::Result<()> {
+        if !self.in_part[file] {
+            return Ok(());
+        }
+
+        // only what was written is there, copied a chunk at a time
+        if let Ok(mut f) = File::open(&self.part) {
+            f.seek(SeekFrom::Start(self.starts[file] as u64))?;
+            let mut f = f.take(self.lengths[file] as u64);
+            let mut buf = vec![0; self.lengths[file].min(FLUSH_SIZE)];
+            let mut offset = 0;
+            loop {
+                let n = f.read(&mut buf)?;
+                if n == 0 {
+                    break;
+                }
+                write_at(&self.paths[file], offset, &buf[..n])?;
+                offset += n;
+            }
+        }
+        self.in_part[file] = false;
+        Ok(())
+    }
+
+    // bytes the wanted files still need on disk to be written in full
+    pub fn needed(&self, wanted: impl Fn(usize) -> bool) -> u64 {
+        (0..self.paths.len())
+            .filter(|i| wanted(*i) && !self.in_part[*i] && !self.padding[*i])
+            .map(|i| (self.lengths[i] as u64).saturating_sub(allocated(&self.paths[i])))
+            .sum()
+    }
+
+    // lays out the wanted files before their pieces arrive
+    pub fn allocate(
+        &self,
+        mode: Allocation,
+        wanted: impl Fn(usize) -> bool,
+    ) -> Result<(), DiskError> {
+        if mode == Allocation::None {
+            return Ok(());
+        }
+
+        for (i, path) in self.paths.iter().enumerate() {
+            if !wanted(i) || self.in_part[i] || self.padding[i] || self.lengths[i] == 0 {
+                continue;
+            }
+            allocate_file(path, self.lengths[i] as u64, mode)
+                .map_err(|e| DiskError::new("allocate", path, e))?;
+        }
+        Ok(())
+    }
+
+    // creates the empty files and symlinks that no piece covers
+    pub fn create_empty(&self, wanted: impl Fn(usize) -> bool) -> Result<(), DiskError> {
+        for (i, path) in self.paths.iter().enumerate() {
+            if !wanted(i) {
+                continue;
+            }
+            match &self.links[i] {
+                // a link made last time is left alone
+                Some(target) if fs::symlink_metadata(path).is_err() => {
+                    symlink(target, path).map_err(|e| DiskError::new("link", path, e))?;
+                }
+                Some(_) => (),
+                None if self.lengths[i] == 0 && !self.padding[i] => {
+                    write_at(path, 0, &[]).map_err(|e| DiskError::new("create", path, e))?;
+                }
+                None => (),
+            }
+        }
+        Ok(())
+    }
+
+    // marks the wanted files that should be run as executable
+    pub fn set_executables(&self, wanted: impl Fn(usize) -> bool) -> Result<(), DiskError> {
+        for (i, path) in self.paths.iter().enumerate() {
+            if self.executable[i] && !self.padding[i] && wanted(i) {
+                set_executable(path).map_err(|e| DiskError::new("chmod", path, e))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn remove_part(&self) {
+        fs::remove_file(&self.part).ok();
+    }
+
+    // size and mtime of each file, to tell if they changed behind our back
+    pub fn states(&self) -> Vec<FileState> {
+        // a link's target may change without us
+        self.paths
+            .iter()
+            .zip(self.padding.iter())
+            .map(|(p, pad)| {
+                if *pad {
+                    FileState::default()
+                } else {
+                    FileState::of(p)
+                }
+            })
+            .collect()
+    }
+
+    pub fn part_state(&self) -> FileState {
+        FileState::of(&self.part)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        copy_then_rename, copy_tree, file_ranges, finish_copy, moving_path, Allocation, Storage,
+    };
+    use crate::resume::FileState;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_file_ranges() {
+        let lengths = [10, 5, 0, 20];
+        assert_eq!(file_ranges(&lengths, 0, 8), vec![(0, 0, 8)]);
+        assert_eq!(
+            file_ranges(&lengths, 8, 24),
+            vec![(0, 8, 2), (1, 0, 5), (3, 0, 9)]
+        );
+        assert_eq!(file_ranges(&lengths, 32, 35), vec![(3, 17, 3)]);
+    }
+
+    #[test]
+    fn test_part_file() {
+        let dir = std::env::temp_dir().join(format!("ntorrent_storage_{}", std::process::id()));
+        let files = vec![
+            (dir.join("a"), 10),
+            (dir.join("sub/b"), 5),
+            (dir.join("c"), 20),
+        ];
+        let mut s = Storage::from_files(files, dir.join("x.part"));
+        s.set_part_files(&[1]);
+
+        let data: Vec<u8> = (0..35).collect();
+        s.write(8, &data[8..24]).unwrap();
+        assert_eq!(s.read(8, 16).unwrap(), &data[8..24]);
+        assert_eq!(std::fs::read(dir.join("a")).unwrap()[8..], data[8..10]);
+        assert!(!dir.join("sub/b").exists());
+        // at its offset in the torrent
+        assert_eq!(
+            std::fs::read(dir.join("x.part")).unwrap()[10..],
+            data[10..15]
+        );
+
+        s.unpart(1).unwrap();
+        assert_eq!(s.part_files(), Vec::<usize>::new());
+        assert_eq!(std::fs::read(dir.join("sub/b")).unwrap(), &data[10..15]);
+        assert_eq!(s.read(8, 16).unwrap(), &data[8..24]);
+        // never written
+        assert!(s.read(24, 11).is_err());
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_move() {
+        let dir = std::env::temp_dir().join(format!("ntorrent_move_{}", std::process::id()));
+        let (inc, done) = (dir.join("inc"), dir.join("done"));
+        let files = vec![(inc.join("t/a"), 10), (inc.join("t/sub/b"), 5)];
+        let mut s = Storage::from_files(files, inc.join("t.part"));
+        s.set_part_files(&[1]);
+
+        let data: Vec<u8> = (0..15).collect();
+        s.write(0, &data).unwrap();
+        let moves = s.moves(&inc, &done).unwrap();
+        s.finish_moves(&moves, &[None, None]).unwrap();
+        assert!(!inc.join("t").exists() && !inc.join("t.part").exists());
+        assert_eq!(std::fs::read(done.join("t/a")).unwrap(), &data[..10]);
+        assert_eq!(s.path(1), done.join("t/sub/b"));
+        assert_eq!(s.read(0, 15).unwrap(), data);
+        // won't overwrite
+        std::fs::create_dir_all(inc.join("t")).unwrap();
+        assert!(s.moves(&done, &inc).is_err());
+        assert!(done.join("t.part").exists());
+
+        // as if it were on another filesystem
+        let mtime = std::fs::metadata(done.join("t/a"))
+            .unwrap()
+            .modified()
+            .unwrap();
+        copy_then_rename(&done.join("t"), &dir.join("copy")).unwrap();
+        assert!(!done.join("t").exists());
+        assert!(!dir.join(".copy.moving").exists());
+        let a = dir.join("copy/a");
+        assert_eq!(std::fs::read(&a).unwrap(), &data[..10]);
+        assert_eq!(std::fs::metadata(&a).unwrap().modified().unwrap(), mtime);
+
+        // copied ahead, then written to before the move is finished
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("gone", dir.join("copy/link")).unwrap();
+        let since = std::time::SystemTime::now();
+        let ahead = dir.join("ahead");
+        copy_tree(&dir.join("copy"), &moving_path(&ahead), None).unwrap();
+        std::fs::write(&a, b"changed").unwrap();
+        finish_copy(&dir.join("copy"), &ahead, since).unwrap();
+        assert_eq!(std::fs::read(ahead.join("a")).unwrap(), b"changed");
+        #[cfg(unix)]
+        assert_eq!(
+            std::fs::read_link(ahead.join("link")).unwrap(),
+            PathBuf::from("gone")
+        );
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_allocate() {
+        assert_eq!("FULL".parse(), Ok(Allocation::Full));
+        assert!("dense".parse::<Allocation>().is_err());
+        assert_eq!(Allocation::Sparse.to_string(), "sparse");
+
+        let dir = std::env::temp_dir().join(format!("ntorrent_allocate_{}", std::process::id()));
+        let files = vec![
+            (dir.join("a"), 1 << 20),
+            (dir.join("sub/b"), 1 << 20),
+            (dir.join("c"), 10),
+        ];
+        let s = Storage::from_files(files, dir.join("x.part"));
+        let wanted = |i| i != 2;
+
+        s.allocate(Allocation::None, wanted).unwrap();
+        assert!(!dir.join("a").exists());
+        assert_eq!(s.needed(wanted), 2 << 20);
+
+        s.allocate(Allocation::Sparse, wanted).unwrap();
+        assert_eq!(std::fs::metadata(dir.join("sub/b")).unwrap().len(), 1 << 20);
+        assert!(!dir.join("c").exists());
+        // still to be written
+        assert!(s.needed(wanted) > 1 << 20);
+
+        s.allocate(Allocation::Full, wanted).unwrap();
+        assert_eq!(std::fs::metadata(dir.join("a")).unwrap().len(), 1 << 20);
+        if cfg!(target_os = "linux") {
+            assert_eq!(s.needed(wanted), 0);
+            assert!(super::free_space(&dir.join("a")).is_some());
+        }
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_attributes() {
+        let dir = std::env::temp_dir().join(format!("ntorrent_attr_{}", std::process::id()));
+        let files = vec![
+            (dir.join("a"), 10),
+            (dir.join(".pad/6"), 6),
+            (dir.join("b"), 4),
+            (dir.join("sub/link"), 0),
+        ];
+        let mut s = Storage::from_files(files, dir.join("x.part"));
+        s.padding[1] = true;
+        s.padding[3] = true;
+        s.executable[0] = true;
+        s.links[3] = Some(PathBuf::from("../a"));
+
+        // padding comes back as zeros without being written
+        let data: Vec<u8> = (1..=20).collect();
+        s.write(0, &data).unwrap();
+        assert!(!dir.join(".pad").exists());
+        let mut expected = data.clone();
+        expected[10..16].copy_from_slice(&[0; 6]);
+        assert_eq!(s.read(0, 20).unwrap(), expected);
+        assert_eq!(s.files_in(8, 10), vec![0, 2]);
+
+        s.set_part_files(&[1, 2]);
+        assert_eq!(s.part_files(), vec![2]);
+        s.set_part_files(&[]);
+
+        s.create_empty(|_| true).unwrap();
+        s.set_executables(|_| true).unwrap();
+        assert_eq!(std::fs::read(dir.join("sub/link")).unwrap(), &data[..10]);
+        // a second time finds the link already there
+        s.create_empty(|_| true).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |p| std::fs::metadata(dir.join(p)).unwrap().permissions().mode();
+            assert_eq!(mode("a") & 0o111, 0o111);
+            assert_eq!(mode("b") & 0o111, 0);
+        }
+        assert_eq!(s.states()[3], FileState::default());
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,280 @@
+use crate::client::Client;
+use crate::messages::messages::Message;
+use crate::messages::ops::{Op, OpType};
+use crate::priority::{Priorities, Priority};
+use crate::utils::bind_dual_stack;
+use crate::utils::bitfield::Bitfield;
+use bytes::Bytes;
+use hyper::header::{ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, RANGE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{body, Body, Method, Request, Response, Server, StatusCode};
+use percent_encoding::percent_decode_str;
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio::sync::broadcast::{self, RecvError};
+use tokio::sync::{mpsc, Mutex};
+
+// parses a single "bytes=" range of a file of length len
+// returns start * end, or None if it can't be served
+pub fn parse_range(header: &str, len: usize) -> Option<(usize, usize)> {
+    let (a, b) = header.trim().strip_prefix("bytes=")?.split_once('-')?;
+    let (start, end) = match (a.trim(), b.trim()) {
+        // the last b bytes
+        ("", b) => (len.saturating_sub(b.parse().ok()?), len),
+        (a, "") => (a.parse().ok()?, len),
+        (a, b) => (a.parse().ok()?, len.min(b.parse::<usize>().ok()? + 1)),
+    };
+    if start >= end {
+        return None;
+    }
+    Some((start, end))
+}
+
+// a file of the torrent as served over HTTP
+struct File {
+    path: String,
+    index: usize,
+    start: usize, // offset in the torrent
+    length: usize,
+}
+
+// serves the files of a torrent over HTTP while they download
+// reads of missing pieces hurry them along and wait for them
+pub struct Streamer {
+    id: u64,
+    files: Vec<File>,
+    piece_length: usize,
+    bf: Arc<Mutex<Bitfield>>,
+    priorities: Arc<Mutex<Priorities>>,
+    btx: broadcast::Sender<Op>,
+    client: Mutex<(mpsc::Sender<Op>, mpsc::Receiver<Op>)>, // requests to the client * answers
+}
+
+impl Streamer {
+    pub fn from_client(
+        c: &Client,
+        i: u64,
+        btx: broadcast::Sender<Op>,
+        mrx: mpsc::Receiver<Op>,
+        tx: mpsc::Sender<Op>,
+    ) -> Streamer {
+        let mut files = Vec::new();
+        let mut start = 0;
+        for (index, (path, f)) in c
+            .torrent
+            .web_paths
+            .iter()
+            .zip(c.torrent.files.iter())
+            .enumerate()
+        {
+            files.push(File {
+                path: format!("/{}", path.join("/")),
+                index,
+                start,
+                length: f.length,
+            });
+            start += f.length;
+        }
+
+        Streamer {
+            id: i,
+            files,
+            piece_length: c.torrent.piece_length as usize,
+            bf: Arc::clone(&c.partial.bf),
+            priorities: Arc::clone(&c.torrent.priorities),
+            btx,
+            client: Mutex::new((tx, mrx)),
+        }
+    }
+
+    // waits until a piece is verified, making it urgent meanwhile
+    // returns None if the client stops first
+    async fn wait_piece(&self, idx: usize) -> Option<()> {
+        // subscribe first so the have can't slip by
+        let mut brx = self.btx.subscribe();
+        if self.bf.lock().await.has(idx) {
+            return Some(());
+        }
+
+        self.priorities.lock().await.hurry(idx);
+        let res = loop {
+            match brx.recv().await {
+                Ok(op) => match op.op_type {
+                    OpType::OpMessage(Message::Have(i)) if i as usize == idx => break Some(()),
+                    OpType::OpStop => break None,
+                    _ => (),
+                },
+                Err(RecvError::Lagged(_)) => {
+                    if self.bf.lock().await.has(idx) {
+                        break Some(());
+                    }
+                }
+                Err(RecvError::Closed) => break None,
+            }
+        };
+        self.priorities.lock().await.unhurry(idx);
+        res
+    }
+
+    // reads part of a verified piece through the client
+    async fn read(&self, idx: usize, offset: usize, len: usize) -> Option<Vec<u8>> {
+        let mut client = self.client.lock().await;
+        client
+            .0
+            .send(Op {
+                id: self.id,
+                op_type: OpType::OpRequest(idx as u32, offset as u32, len as u32),
+            })
+            .await
+            .ok()?;
+
+        match client.1.recv().await?.op_type {
+            OpType::OpMessage(Message::Piece(_, _, buf)) => Some(buf),
+            _ => None,
+        }
+    }
+
+    // sends the bytes from start to end of the torrent as their pieces arrive
+    async fn send_range(self: Arc<Self>, mut start: usize, end: usize, mut tx: body::Sender) {
+        while start < end {
+            let idx = start / self.piece_length;
+            let offset = start % self.piece_length;
+            let len = (end - start).min(self.piece_length - offset);
+
+            let buf = match self.wait_piece(idx).await {
+                Some(()) => self.read(idx, offset, len).await,
+                None => None,
+            };
+            let sent = match buf {
+                Some(buf) => tx.send_data(Bytes::from(buf)).await.is_ok(),
+                None => false,
+            };
+            if !sent {
+                tx.abort();
+                return;
+            }
+            start += len;
+        }
+    }
+
+    // lists the paths of the files that aren't skipped
+    async fn index(&self) -> String {
+        let prio = self.priorities.lock().await;
+        self.files
+            .iter()
+            .filter(|f| prio.file(f.index) != Priority::Skip)
+            .map(|f| format!("{}\n", f.path))
+            .collect()
+    }
+}
+
+fn status(code: StatusCode) -> Response<Body> {
+    let mut res = Response::new(Body::from(code.to_string()));
+    *res.status_mut() = code;
+    res
+}
+
+async fn handle(s: Arc<Streamer>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return Ok(status(StatusCode::METHOD_NOT_ALLOWED));
+    }
+
+    let path = percent_decode_str(req.uri().path()).decode_utf8_lossy();
+    if path == "/" {
+        return Ok(Response::new(Body::from(s.index().await)));
+    }
+
+    let file = match s.files.iter().find(|f| f.path == path) {
+        Some(f) => f,
+        None => return Ok(status(StatusCode::NOT_FOUND)),
+    };
+    if s.priorities.lock().await.file(file.index) == Priority::Skip {
+        return Ok(status(StatusCode::NOT_FOUND));
+    }
+
+    let range = match req.headers().get(RANGE) {
+        Some(r) => match r.to_str().ok().and_then(|r| parse_range(r, file.length)) {
+            Some(r) => Some(r),
+            None => {
+                let mut res = status(StatusCode::RANGE_NOT_SATISFIABLE);
+                let all = format!("bytes */{}", file.length);
+                res.headers_mut()
+                    .insert(CONTENT_RANGE, all.parse().unwrap());
+                return Ok(res);
+            }
+        },
+        None => None,
+    };
+    let (start, end) = range.unwrap_or((0, file.length));
+
+    let mut res = Response::builder()
+        .header(ACCEPT_RANGES, "bytes")
+        .header(CONTENT_LENGTH, end - start);
+    if range.is_some() {
+        res = res.status(StatusCode::PARTIAL_CONTENT).header(
+            CONTENT_RANGE,
+            format!("bytes {}-{}/{}", start, end - 1, file.length),
+        );
+    }
+    if req.method() == Method::HEAD || start == end {
+        return Ok(res.body(Body::empty()).unwrap());
+    }
+
+    // sequential files continue from here
+    s.priorities.lock().await.seek(file.index, start);
+
+    let (tx, body) = Body::channel();
+    let (start, end) = (file.start + start, file.start + end);
+    tokio::spawn(Arc::clone(&s).send_range(start, end, tx));
+    Ok(res.body(body).unwrap())
+}
+
+// serves the files on port until the client stops
+pub async fn serve(streamer: Streamer, port: u16) {
+    let mut brx = streamer.btx.subscribe();
+    let streamer = Arc::new(streamer);
+
+    let make_svc = make_service_fn(move |_| {
+        let streamer = Arc::clone(&streamer);
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle(Arc::clone(&streamer), req))) }
+    });
+
+    let listener = bind_dual_stack(port)
+        .or_else(|_| std::net::TcpListener::bind(("0.0.0.0", port)))
+        .expect("Can't bind to port!");
+    let server = Server::from_tcp(listener).expect("Can't bind to port!");
+
+    println!("Serving files over HTTP on port {}", port);
+    let res = server
+        .serve(make_svc)
+        .with_graceful_shutdown(async move {
+            loop {
+                match brx.recv().await {
+                    Ok(op) if op.op_type == OpType::OpStop => break,
+                    Err(RecvError::Closed) => break,
+                    _ => (),
+                }
+            }
+        })
+        .await;
+    if let Err(e) = res {
+        println!("HTTP server error: {}", e);
+    }
+    println!("HTTP server stopping");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_range;
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-9", 100), Some((0, 10)));
+        assert_eq!(parse_range("bytes=90-", 100), Some((90, 100)));
+        assert_eq!(parse_range("bytes=-10", 100), Some((90, 100)));
+        assert_eq!(parse_range("bytes=50-500", 100), Some((50, 100)));
+        assert_eq!(parse_range("bytes=100-", 100), None);
+        assert_eq!(parse_range("bytes=0-1,5-6", 100), None);
+        assert_eq!(parse_range("items=0-9", 100), None);
+    }
+}
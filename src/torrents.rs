@@ -1,16 +1,52 @@
+use crate::priority::{Order, Priorities, Priority};
+use crate::sanitize;
 use crate::utils::queue::Queue;
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
 use sha1::{Digest, Sha1};
 use std::collections::VecDeque;
+use std::fmt;
 use std::fs;
+use std::sync::Arc;
+use tokio::sync::Mutex;
 
 // The following three structs are used for
 // serde decoding of bencoded torrent files
 #[derive(Serialize, Deserialize, Debug)]
 pub struct FileInfo {
     pub length: usize,
+    #[serde(deserialize_with = "sanitize::lossy_list")]
     pub path: Vec<String>,
+    #[serde(default)]
+    pub attr: Option<String>, // p padding, x executable, h hidden, l symlink (BEP 47)
+    #[serde(default, rename = "symlink path")]
+    pub symlink_path: Option<Vec<String>>, // under the torrent, made relative to the link
+    #[serde(default)]
+    pub sha1: Option<ByteBuf>,
+}
+
+impl FileInfo {
+    fn has_attr(&self, c: char) -> bool {
+        self.attr.as_ref().is_some_and(|a| a.contains(c))
+    }
+
+    // filler so the next file starts on a piece, never written
+    pub fn is_padding(&self) -> bool {
+        self.has_attr('p')
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.has_attr('x')
+    }
+
+    // only listed, as hidden means a leading dot where we run
+    pub fn is_hidden(&self) -> bool {
+        self.has_attr('h')
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.has_attr('l') && self.symlink_path.is_some()
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -19,31 +55,117 @@ struct Info {
     files: Option<Vec<FileInfo>>,
     #[serde(default)]
     pub length: Option<usize>,
+    #[serde(default)]
+    pub attr: Option<String>, // of the file of a single file torrent
+    #[serde(deserialize_with = "sanitize::lossy")]
     pub name: String,
     #[serde(rename = "piece length")]
     pub piece_length: u32,
     pub pieces: ByteBuf,
+    #[serde(default)]
+    pub private: Option<i64>, // BEP 27
 }
 
-impl Info {
-    pub fn hash(&self) -> Vec<u8> {
-        let mut hash = Sha1::new();
-        let info = serde_bencode::to_bytes(&self).expect("Could not encode info hash!");
-        hash.input(info.as_slice());
-        hash.result().as_slice().to_vec()
+// where the bencoded value starting at i ends
+fn bencode_end(buf: &[u8], i: usize) -> Option<usize> {
+    match *buf.get(i)? {
+        b'i' => Some(i + buf[i..].iter().position(|&c| c == b'e')? + 1),
+        b'l' | b'd' => {
+            let mut j = i + 1;
+            while *buf.get(j)? != b'e' {
+                j = bencode_end(buf, j)?;
+            }
+            Some(j + 1)
+        }
+        b'0'..=b'9' => {
+            let colon = i + buf[i..].iter().position(|&c| c == b':')?;
+            let len: usize = std::str::from_utf8(&buf[i..colon]).ok()?.parse().ok()?;
+            let end = colon + 1 + len;
+            if end > buf.len() {
+                return None;
+            }
+            Some(end)
+        }
+        _ => None,
+    }
+}
+
+// the info dict as it appears in the torrent file
+// it is hashed as is, so keys we don't know about still count
+fn info_bytes(buf: &[u8]) -> Option<&[u8]> {
+    if buf.first() != Some(&b'd') {
+        return None;
+    }
+
+    let mut i = 1;
+    while *buf.get(i)? != b'e' {
+        let key = i;
+        i = bencode_end(buf, i)?;
+        let end = bencode_end(buf, i)?;
+        if &buf[key..i] == b"4:info" {
+            return Some(&buf[i..end]);
+        }
+        i = end;
+    }
+    None
+}
+
+// a metainfo key that can hold one string or a list of them
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl OneOrMany {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            OneOrMany::One(s) => vec![s],
+            OneOrMany::Many(v) => v,
+        }
     }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 struct TorrentFile {
     pub announce: String,
+    #[serde(default, rename = "announce-list")]
+    pub announce_list: Option<Vec<Vec<String>>>, // tiers of trackers (BEP 12)
+    #[serde(default, rename = "url-list")]
+    pub url_list: Option<OneOrMany>, // web seeds (BEP 19)
+    #[serde(default)]
+    pub httpseeds: Option<OneOrMany>, // seeding scripts (BEP 17)
     pub info: Info,
 }
 
 impl TorrentFile {
-    fn new(s: &str) -> TorrentFile {
+    // the torrent along with its info hash
+    fn new(s: &str) -> (TorrentFile, Vec<u8>) {
         let contents = fs::read(&s).expect("Could not read file!");
-        serde_bencode::de::from_bytes(contents.as_slice()).expect("Could not decode torrent file!")
+        let t = serde_bencode::de::from_bytes(contents.as_slice())
+            .expect("Could not decode torrent file!");
+
+        let info = info_bytes(&contents).expect("Could not find info in torrent file!");
+        let mut hash = Sha1::new();
+        hash.input(info);
+        (t, hash.result().as_slice().to_vec())
+    }
+}
+
+// a torrent with nowhere safe to put its files, see sanitize
+#[derive(Debug)]
+pub enum TorrentError {
+    NoName,
+    NoPath(usize), // the file at this index
+}
+
+impl fmt::Display for TorrentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TorrentError::NoName => write!(f, "Torrent with no name!"),
+            TorrentError::NoPath(i) => write!(f, "File {} with no path in torrent!", i),
+        }
     }
 }
 
@@ -68,12 +190,21 @@ impl Piece {
 pub struct Torrent {
     pub name: String,
     pub announce: String,
+    pub trackers: Vec<String>, // announce followed by the announce-list
+    pub url_list: Vec<String>,
+    pub web_paths: Vec<Vec<String>>, // path of each file under a web seed
+    pub http_seeds: Vec<String>,
     pub piece_length: u32,
     pub info_hash: Vec<u8>,
-    pub pieces: Queue<Piece>,
+    pub hashes: Vec<Piece>,   // every piece, in order
+    pub pieces: Queue<Piece>, // pieces still to download
+    pub priorities: Arc<Mutex<Priorities>>,
     pub files: Vec<FileInfo>,
     pub peer_id: Vec<u8>,
     pub length: usize,
+    // peers may only come from the torrent's own trackers (BEP 27)
+    // there is no DHT, PEX or local discovery to turn off yet
+    pub private: bool,
 }
 
 pub fn split_hash(pieces: Vec<u8>, piece_length: usize, length: usize) -> VecDeque<Piece> {
@@ -106,30 +237,63 @@ pub fn split_hash(pieces: Vec<u8>, piece_length: usize, length: usize) -> VecDeq
 }
 
 impl Torrent {
-    pub fn new(s: &str, dir: &str) -> Torrent {
-        let t = TorrentFile::new(s);
-        let info_hash = t.info.hash();
+    pub fn new(s: &str, dir: &str) -> Result<Torrent, TorrentError> {
+        let (t, info_hash) = TorrentFile::new(s);
 
         // randomly generate id
         let id: [u8; 20] = rand::random();
 
+        // nothing is created under the name as given, see sanitize
+        let name = sanitize::component(&t.info.name).unwrap_or_default();
+
         let mut files;
+        let web_paths;
         if let Some(file) = t.info.files {
             files = file;
+            let raw_name = &t.info.name;
+            web_paths = files
+                .iter()
+                .map(|f| {
+                    let mut path = vec![raw_name.clone()];
+                    path.extend(f.path.iter().cloned());
+                    path
+                })
+                .collect();
 
-            // append base dir in multidoc format
-            if t.info.name.len() > 0 {
-                for f in files.iter_mut() {
-                    f.path.insert(0, t.info.name.clone());
+            let mut names = sanitize::Names::default();
+            for (i, f) in files.iter_mut().enumerate() {
+                let path = sanitize::path(&f.path).ok_or(TorrentError::NoPath(i))?;
+                f.path = names.unique(path);
+            }
 
-                    if dir != "" {
-                        f.path.insert(0, dir.to_string());
-                    }
+            // link targets are given from the top of the torrent, with the names it gave
+            for f in files.iter_mut() {
+                f.symlink_path = f
+                    .symlink_path
+                    .as_ref()
+                    .and_then(|p| sanitize::path(p))
+                    .map(|p| names.resolve(p));
+                if let Some(target) = f.symlink_path.as_mut() {
+                    let up = vec!["..".to_string(); f.path.len().saturating_sub(1)];
+                    target.splice(0..0, up);
+                }
+            }
+
+            // append base dir in multidoc format
+            for f in files.iter_mut() {
+                if !name.is_empty() {
+                    f.path.insert(0, name.clone());
+                }
+                if !dir.is_empty() {
+                    f.path.insert(0, dir.to_string());
                 }
             }
         } else {
             // if only one file, create new FileInfo
-            let mut path = vec![t.info.name.clone()];
+            if name.is_empty() {
+                return Err(TorrentError::NoName);
+            }
+            let mut path = vec![name.clone()];
 
             if dir != "" {
                 path.insert(0, dir.to_string());
@@ -137,25 +301,79 @@ impl Torrent {
             files = vec![FileInfo {
                 length: t.info.length.unwrap(),
                 path,
-            }]
+                attr: t.info.attr,
+                symlink_path: None,
+                sha1: None,
+            }];
+            web_paths = vec![vec![t.info.name.clone()]];
         };
 
         let length = files.iter().map(|x| x.length).fold(0, |a, b| a + b);
+        let lengths: Vec<usize> = files.iter().map(|f| f.length).collect();
+        let mut priorities = Priorities::new(&lengths, t.info.piece_length as usize);
+        for (i, f) in files.iter().enumerate() {
+            if f.is_padding() {
+                priorities.set_file(i, Priority::Skip);
+            }
+        }
+
+        let pieces = split_hash(
+            t.info.pieces.into_vec(),
+            t.info.piece_length as usize,
+            length,
+        );
 
-        Torrent {
-            name: t.info.name,
+        let mut trackers = vec![t.announce.clone()];
+        for url in t.announce_list.into_iter().flatten().flatten() {
+            if !trackers.contains(&url) {
+                trackers.push(url);
+            }
+        }
+
+        Ok(Torrent {
+            name,
             announce: t.announce,
+            trackers,
+            url_list: t.url_list.map_or(Vec::new(), OneOrMany::into_vec),
+            web_paths,
+            http_seeds: t.httpseeds.map_or(Vec::new(), OneOrMany::into_vec),
             piece_length: t.info.piece_length,
             info_hash,
-            pieces: Queue::from(split_hash(
-                t.info.pieces.into_vec(),
-                t.info.piece_length as usize,
-                length,
-            )),
+            hashes: pieces.iter().cloned().collect(),
+            pieces: Queue::from(pieces),
+            priorities: Arc::new(Mutex::new(priorities)),
             files,
             peer_id: id.as_ref().to_vec(),
             length,
-        }
+            private: t.info.private == Some(1),
+        })
+    }
+
+    // returns None if there is no such file
+    pub async fn set_priority(&self, file: usize, p: Priority) -> Option<()> {
+        self.priorities.lock().await.set_file(file, p)?;
+
+        // pieces may have become wanted
+        self.pieces.wake();
+        Some(())
+    }
+
+    // stops handing out pieces until unpaused
+    pub async fn pause(&self, paused: bool) {
+        self.priorities.lock().await.pause(paused);
+        self.pieces.wake();
+    }
+
+    // returns None if there is no such file
+    pub async fn set_order(&self, file: usize, o: Order) -> Option<()> {
+        self.priorities.lock().await.set_order(file, o)
+    }
+
+    // moves the streaming cursor of file to offset, so its pieces
+    // from there on are picked first if it is sequential or has a deadline
+    // returns None if there is no such byte
+    pub async fn seek(&self, file: usize, offset: usize) -> Option<()> {
+        self.priorities.lock().await.seek(file, offset)
     }
 }
 
@@ -182,6 +400,18 @@ mod tests {
         assert_eq!(r[2].2, 2);
     }
 
+    #[test]
+    fn test_info_bytes() {
+        let buf = b"d8:announce3:abc4:infod6:lengthi5e1:x4:spam4:nameli1eee3:zzzi0ee";
+        assert_eq!(
+            super::info_bytes(buf),
+            Some(&b"d6:lengthi5e1:x4:spam4:nameli1eee"[..])
+        );
+        assert_eq!(super::info_bytes(b"d4:infod1:xi1e"), None);
+        assert_eq!(super::info_bytes(b"d4:info5:abce"), None);
+        assert_eq!(super::info_bytes(b"d3:abci1ee"), None);
+    }
+
     #[test]
     #[should_panic]
     fn test_panic() {
@@ -0,0 +1,433 @@
+use crate::ipfilter;
+use crate::scrape::ScrapeInfo;
+use crate::utils::bind_dual_stack;
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use percent_encoding::percent_decode;
+use rand::seq::SliceRandom;
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, Mutex};
+
+// peers returned when the announce doesn't say how many it wants
+const DEFAULT_NUMWANT: usize = 50;
+const MAX_NUMWANT: usize = 200;
+
+#[derive(Serialize, Debug)]
+struct PeerInfo {
+    pub ip: String,
+    #[serde(rename = "peer id")]
+    pub peer_id: ByteBuf,
+    pub port: u16,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+enum Peers {
+    Compact(ByteBuf),
+    Dict(Vec<PeerInfo>),
+}
+
+#[derive(Serialize, Debug)]
+struct AnnounceResponse {
+    pub interval: u64,
+    #[serde(rename = "min interval")]
+    pub min_interval: u64,
+    pub complete: u64,
+    pub incomplete: u64,
+    pub peers: Peers,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peers6: Option<ByteBuf>,
+}
+
+#[derive(Serialize, Debug)]
+struct ScrapeResponse {
+    pub files: HashMap<ByteBuf, ScrapeInfo>,
+}
+
+#[derive(Serialize, Debug)]
+struct FailureResponse {
+    #[serde(rename = "failure reason")]
+    pub failure_reason: String,
+}
+
+// splits a query string, percent decoding values into raw bytes
+fn parse_query(query: &str) -> Vec<(String, Vec<u8>)> {
+    let decode = |s: &str| percent_decode(s.replace('+', " ").as_bytes()).collect::<Vec<u8>>();
+
+    query
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            let (k, v) = p.split_once('=').unwrap_or((p, ""));
+            (String::from_utf8_lossy(&decode(k)).into_owned(), decode(v))
+        })
+        .collect()
+}
+
+fn param<'a>(params: &'a [(String, Vec<u8>)], key: &str) -> Option<&'a [u8]> {
+    params
+        .iter()
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| v.as_slice())
+}
+
+fn parse_param<T: FromStr>(params: &[(String, Vec<u8>)], key: &str) -> Option<T> {
+    std::str::from_utf8(param(params, key)?).ok()?.parse().ok()
+}
+
+// parses a hex encoded info hash
+pub fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    let s = s.trim();
+    if s.len() != 40 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[derive(Debug)]
+struct Peer {
+    addr: SocketAddr,
+    seeding: bool,
+    last: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Swarm {
+    peers: HashMap<Vec<u8>, Peer>, // keyed by peer id
+    downloaded: u64,
+}
+
+impl Swarm {
+    fn info(&self) -> ScrapeInfo {
+        let complete = self.peers.values().filter(|p| p.seeding).count() as u64;
+        ScrapeInfo {
+            complete,
+            incomplete: self.peers.len() as u64 - complete,
+            downloaded: self.downloaded,
+        }
+    }
+}
+
+// an HTTP tracker (BEP 3, BEP 23 and BEP 48 scrape)
+// peers that stop announcing are forgotten after two intervals
+pub struct Tracker {
+    swarms: HashMap<Vec<u8>, Swarm>, // keyed by info hash
+    whitelist: Option<HashSet<Vec<u8>>>,
+    interval: Duration,
+}
+
+impl Tracker {
+    pub fn new(interval: Duration) -> Tracker {
+        Tracker {
+            swarms: HashMap::new(),
+            whitelist: None,
+            interval,
+        }
+    }
+
+    // only tracks the torrents whose info hashes are listed, one hex hash per line
+    pub fn set_whitelist(&mut self, path: &str) {
+        let text = std::fs::read_to_string(path).expect("Could not read whitelist!");
+        let hashes: HashSet<Vec<u8>> = text.lines().filter_map(parse_hex).collect();
+        println!("Tracking {} whitelisted torrents", hashes.len());
+        self.whitelist = Some(hashes);
+    }
+
+    fn expire(&mut self) {
+        let timeout = self.interval * 2;
+        for swarm in self.swarms.values_mut() {
+            swarm.peers.retain(|_, p| p.last.elapsed() < timeout);
+        }
+        self.swarms
+            .retain(|_, s| !s.peers.is_empty() || s.downloaded > 0);
+    }
+
+    // handles an announce from addr, returning the bencoded response
+    pub fn announce(&mut self, addr: SocketAddr, query: &str) -> Vec<u8> {
+        match self.try_announce(addr, query) {
+            Ok(res) => serde_bencode::to_bytes(&res),
+            Err(reason) => serde_bencode::to_bytes(&FailureResponse {
+                failure_reason: reason.to_string(),
+            }),
+        }
+        .expect("Could not encode tracker response!")
+    }
+
+    fn try_announce(
+        &mut self,
+        addr: SocketAddr,
+        query: &str,
+    ) -> Result<AnnounceResponse, &'static str> {
+        let params = parse_query(query);
+        let info_hash = param(&params, "info_hash")
+            .filter(|h| h.len() == 20)
+            .ok_or("missing info_hash")?
+            .to_vec();
+        let peer_id = param(&params, "peer_id")
+            .filter(|h| h.len() == 20)
+            .ok_or("missing peer_id")?
+            .to_vec();
+        let port: u16 = parse_param(&params, "port").ok_or("missing port")?;
+        let left: u64 = parse_param(&params, "left").ok_or("missing left")?;
+        let compact = parse_param::<u8>(&params, "compact") == Some(1);
+        let numwant = parse_param(&params, "numwant")
+            .unwrap_or(DEFAULT_NUMWANT)
+            .min(MAX_NUMWANT);
+        let event = param(&params, "event").unwrap_or(b"");
+
+        if let Some(whitelist) = &self.whitelist {
+            if !whitelist.contains(&info_hash) {
+                return Err("torrent not tracked");
+            }
+        }
+
+        self.expire();
+        let swarm = self.swarms.entry(info_hash).or_default();
+        if event == b"stopped" {
+            swarm.peers.remove(&peer_id);
+        } else {
+            if event == b"completed" {
+                swarm.downloaded += 1;
+            }
+            let ip = ipfilter::canonical(&addr.ip());
+            swarm.peers.insert(
+                peer_id.clone(),
+                Peer {
+                    addr: SocketAddr::new(ip, port),
+                    seeding: left == 0,
+                    last: Instant::now(),
+                },
+            );
+        }
+
+        let others: Vec<(&Vec<u8>, &Peer)> = swarm
+            .peers
+            .iter()
+            .filter(|(id, _)| **id != peer_id)
+            .collect();
+        let chosen = others.choose_multiple(&mut rand::thread_rng(), numwant);
+
+        let info = swarm.info();
+        let mut res = AnnounceResponse {
+            interval: self.interval.as_secs(),
+            min_interval: self.interval.as_secs() / 2,
+            complete: info.complete,
+            incomplete: info.incomplete,
+            peers: Peers::Dict(Vec::new()),
+            peers6: None,
+        };
+
+        if compact {
+            let mut peers = Vec::new();
+            let mut peers6 = Vec::new();
+            for (_, p) in chosen {
+                let (buf, ip) = match p.addr.ip() {
+                    IpAddr::V4(ip) => (&mut peers, ip.octets().to_vec()),
+                    IpAddr::V6(ip) => (&mut peers6, ip.octets().to_vec()),
+                };
+                buf.extend(ip);
+                buf.extend(&p.addr.port().to_be_bytes());
+            }
+            res.peers = Peers::Compact(ByteBuf::from(peers));
+            if !peers6.is_empty() {
+                res.peers6 = Some(ByteBuf::from(peers6));
+            }
+        } else {
+            res.peers = Peers::Dict(
+                chosen
+                    .map(|(id, p)| PeerInfo {
+                        ip: p.addr.ip().to_string(),
+                        peer_id: ByteBuf::from(id.to_vec()),
+                        port: p.addr.port(),
+                    })
+                    .collect(),
+            );
+        }
+        Ok(res)
+    }
+
+    // handles a scrape, returning the bencoded response
+    // scraping without an info_hash returns every torrent
+    pub fn scrape(&mut self, query: &str) -> Vec<u8> {
+        self.expire();
+
+        let params = parse_query(query);
+        let hashes: Vec<&Vec<u8>> = params
+            .iter()
+            .filter(|(k, _)| k == "info_hash")
+            .map(|(_, v)| v)
+            .collect();
+
+        let mut files = HashMap::new();
+        for (hash, swarm) in self.swarms.iter() {
+            if hashes.is_empty() || hashes.contains(&hash) {
+                files.insert(ByteBuf::from(hash.clone()), swarm.info());
+            }
+        }
+        serde_bencode::to_bytes(&ScrapeResponse { files })
+            .expect("Could not encode tracker response!")
+    }
+}
+
+async fn handle(
+    tracker: Arc<Mutex<Tracker>>,
+    addr: SocketAddr,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let query = req.uri().query().unwrap_or("");
+    let body = match req.uri().path() {
+        "/announce" => tracker.lock().await.announce(addr, query),
+        "/scrape" => tracker.lock().await.scrape(query),
+        _ => {
+            let mut res = Response::new(Body::from("not found"));
+            *res.status_mut() = StatusCode::NOT_FOUND;
+            return Ok(res);
+        }
+    };
+    Ok(Response::new(Body::from(body)))
+}
+
+// serves the tracker on port until ctrl-c
+pub async fn serve(tracker: Tracker, port: u16) {
+    let tracker = Arc::new(Mutex::new(tracker));
+
+    let make_svc = make_service_fn(move |conn: &AddrStream| {
+        let tracker = Arc::clone(&tracker);
+        let addr = conn.remote_addr();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                handle(Arc::clone(&tracker), addr, req)
+            }))
+        }
+    });
+
+    let listener = bind_dual_stack(port)
+        .or_else(|_| std::net::TcpListener::bind(("0.0.0.0", port)))
+        .expect("Can't bind to port!");
+    let server = Server::from_tcp(listener).expect("Can't bind to port!");
+
+    let (tx, mut erx) = broadcast::channel(1);
+    ctrlc::set_handler(move || {
+        if tx.send(()).is_err() {
+            std::process::exit(1)
+        }
+    })
+    .expect("Could not register SIGINT handler");
+
+    println!("Tracker listening on port {}", port);
+    let res = server
+        .serve(make_svc)
+        .with_graceful_shutdown(async move {
+            erx.recv().await.ok();
+        })
+        .await;
+    if let Err(e) = res {
+        println!("Tracker error: {}", e);
+    }
+    println!("Tracker stopping");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_hex, parse_query, Tracker};
+    use crate::utils::serialize_bytes;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    fn query(hash: u8, id: u8, port: u16, extra: &str) -> String {
+        format!(
+            "info_hash={}&peer_id={}&port={}&uploaded=0&downloaded=0{}",
+            serialize_bytes(&vec![hash; 20]),
+            serialize_bytes(&vec![id; 20]),
+            port,
+            extra
+        )
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let params = parse_query("info_hash=%00%2B+a&compact=1&x");
+        assert_eq!(
+            params[0],
+            ("info_hash".to_string(), vec![0, b'+', b' ', b'a'])
+        );
+        assert_eq!(params[1], ("compact".to_string(), b"1".to_vec()));
+        assert_eq!(params[2], ("x".to_string(), vec![]));
+
+        assert_eq!(parse_hex(&"ab".repeat(20)), Some(vec![0xab; 20]));
+        assert_eq!(parse_hex("abc"), None);
+    }
+
+    #[test]
+    fn test_announce() {
+        let mut t = Tracker::new(Duration::from_secs(60));
+        let a: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        let b: SocketAddr = "[::ffff:10.0.0.2]:1000".parse().unwrap();
+
+        let res = t.announce(a, &query(1, 1, 6881, "&left=0&compact=1"));
+        assert_eq!(
+            res,
+            b"d8:completei1e10:incompletei0e8:intervali60e12:min intervali30e5:peers0:e".to_vec()
+        );
+
+        let res = t.announce(b, &query(1, 2, 6882, "&left=5&compact=1&event=started"));
+        assert_eq!(
+            res,
+            b"d8:completei1e10:incompletei1e8:intervali60e12:min intervali30e\
+              5:peers6:\x0a\x00\x00\x01\x1a\xe1e"
+                .to_vec()
+        );
+
+        let res = t.announce(a, &query(1, 1, 6881, "&left=0"));
+        let mut expected = b"d8:completei1e10:incompletei1e8:intervali60e12:min intervali30e\
+              5:peersld2:ip8:10.0.0.27:peer id20:"
+            .to_vec();
+        expected.extend(&[2; 20]);
+        expected.extend(b"4:porti6882eeee");
+        assert_eq!(res, expected);
+
+        t.announce(b, &query(1, 2, 6882, "&left=0&event=completed"));
+        t.announce(a, &query(1, 1, 6881, "&left=0&event=stopped"));
+        let res = t.scrape(&format!("info_hash={}", serialize_bytes(&vec![1; 20])));
+        let mut expected = b"d5:filesd20:".to_vec();
+        expected.extend(&[1; 20]);
+        expected.extend(b"d8:completei1e10:downloadedi1e10:incompletei0eeee");
+        assert_eq!(res, expected);
+    }
+
+    #[test]
+    fn test_failures() {
+        let mut t = Tracker::new(Duration::from_secs(60));
+        let a: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        assert_eq!(
+            t.announce(a, "port=1"),
+            b"d14:failure reason17:missing info_hashe".to_vec()
+        );
+
+        t.whitelist = Some(vec![vec![2; 20]].into_iter().collect());
+        assert_eq!(
+            t.announce(a, &query(1, 1, 6881, "&left=0")),
+            b"d14:failure reason19:torrent not trackede".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_expiry() {
+        let mut t = Tracker::new(Duration::from_millis(1));
+        let a: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        t.announce(a, &query(1, 1, 6881, "&left=0"));
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(t.scrape(""), b"d5:filesdee".to_vec());
+    }
+}
@@ -1,7 +1,10 @@
 use crate::consts::BLOCKSIZE;
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::net::{Ipv6Addr, SocketAddr, TcpListener};
 
 pub mod bitfield;
 pub mod queue;
+pub mod rate;
 
 pub fn serialize_bytes(b: &Vec<u8>) -> String {
     url::form_urlencoded::byte_serialize(b.as_slice())
@@ -18,3 +21,17 @@ pub fn calc_request(start: u32, len: u32) -> u32 {
     }
     l
 }
+
+// binds a listener accepting both ipv4 and ipv6 connections
+pub fn bind_dual_stack(port: u16) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::ipv6(), Type::stream(), Some(Protocol::tcp()))?;
+    socket.set_only_v6(false)?;
+    socket.set_reuse_address(true)?;
+    let addr = SocketAddr::from((Ipv6Addr::UNSPECIFIED, port));
+    socket.bind(&SockAddr::from(addr))?;
+    socket.listen(128)?;
+
+    let listener = socket.into_tcp_listener();
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
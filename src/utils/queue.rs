@@ -16,6 +16,13 @@ impl<T> Queue<T> {
         self.cond.notify();
     }
 
+    // pushes to the front so the item is picked up next
+    pub async fn push_front(&self, x: T) {
+        let mut q = self.q.lock().await;
+        q.push_front(x);
+        self.cond.notify();
+    }
+
     pub fn get_q(&self) -> Arc<Mutex<VecDeque<T>>> {
         Arc::clone(&self.q)
     }
@@ -25,25 +32,36 @@ impl<T> Queue<T> {
         q.len()
     }
 
-    // find first item that satisfies the f filter
+    // removes the item ranked highest by f, earlier items winning ties
+    // items f ranks None are left alone
     // does not block
-    pub async fn find_first<F>(&self, f: F) -> Option<T>
+    pub async fn find_best<F, R>(&self, f: F) -> Option<T>
     where
-        F: Fn(&T) -> bool,
+        F: Fn(&T) -> Option<R>,
+        R: Ord,
     {
         let mut q = self.q.lock().await;
-        let n = q.len();
-        for _ in 0..n {
-            let item = q.pop_front().unwrap();
-            if f(&item) {
-                self.cond.notify();
-                return Some(item);
-            } else {
-                q.push_back(item);
+        let mut best: Option<(usize, R)> = None;
+        for (i, item) in q.iter().enumerate() {
+            if let Some(r) = f(item) {
+                if best.as_ref().is_none_or(|(_, b)| r > *b) {
+                    best = Some((i, r));
+                }
             }
         }
+        let res = q.remove(best?.0);
+        self.cond.notify();
+        res
+    }
+
+    // waits for the queue to change, or for wake
+    pub async fn wait(&self) {
+        self.cond.notified().await;
+    }
+
+    // wakes a waiter, for when what it is waiting for may have changed
+    pub fn wake(&self) {
         self.cond.notify();
-        None
     }
 
     // blocking pop
@@ -62,11 +80,6 @@ impl<T> Queue<T> {
         }
     }
 
-    pub async fn clear(&self) {
-        let mut q = self.q.lock().await;
-        *q = VecDeque::new();
-    }
-
     // nonblocking pop, can return None
     // pub async fn pop(&mut self) -> Option<T> {
     //     let mut q = self.q.lock().await;
@@ -129,6 +142,18 @@ mod test {
         assert_eq!(q.pop_block().await, 2);
     }
 
+    #[tokio::test]
+    async fn test_find_best() {
+        let q = Queue::from(vec![1, 4, 2, 4, 3].into_iter().collect());
+
+        let even = |x: &i32| if x % 2 == 0 { Some(*x) } else { None };
+        assert_eq!(q.find_best(even).await, Some(4));
+        assert_eq!(q.find_best(even).await, Some(4));
+        assert_eq!(q.find_best(even).await, Some(2));
+        assert_eq!(q.find_best(even).await, None);
+        assert_eq!(q.len().await, 2);
+    }
+
     #[tokio::test]
     async fn test_block() {
         let mut q = Queue::<i64>::new();
@@ -0,0 +1,45 @@
+use std::time::{Duration, Instant};
+
+const WINDOW: Duration = Duration::from_secs(1);
+
+// smoothed transfer rate in bytes per second
+#[derive(Debug)]
+pub struct Rate {
+    rate: f64,
+    bytes: usize,
+    last: Instant,
+}
+
+impl Rate {
+    pub fn new() -> Rate {
+        Rate {
+            rate: 0.0,
+            bytes: 0,
+            last: Instant::now(),
+        }
+    }
+
+    pub fn add(&mut self, n: usize) {
+        self.bytes += n;
+        self.tick();
+    }
+
+    // returns the current rate
+    pub fn get(&mut self) -> f64 {
+        self.tick();
+        self.rate
+    }
+
+    // folds bytes into the average once a window has passed
+    fn tick(&mut self) {
+        let elapsed = self.last.elapsed();
+        if elapsed < WINDOW {
+            return;
+        }
+
+        let sample = self.bytes as f64 / elapsed.as_secs_f64();
+        self.rate = (self.rate + sample) / 2.0;
+        self.bytes = 0;
+        self.last = Instant::now();
+    }
+}
@@ -0,0 +1,348 @@
+use crate::client::Client;
+use crate::consts::{WEBSEED_MAX_RETRY, WEBSEED_RETRY, WEBSEED_TIMEOUT};
+use crate::downloading::Downloading;
+use crate::hasher::Hasher;
+use crate::messages::ops::{Op, OpType};
+use crate::peerlist::tracker_client;
+use crate::priority::Priorities;
+use crate::storage::file_ranges;
+use crate::torrents::{Piece, Torrent};
+use crate::utils::queue::Queue;
+use crate::utils::serialize_bytes;
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use reqwest::header::RANGE;
+use reqwest::StatusCode;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, Mutex};
+
+// characters escaped in path segments of web seed URLs
+const SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+// URL of each file of the torrent on a web seed (BEP 19)
+// a single file torrent's URL can name the file itself
+pub fn file_urls(base: &str, torrent: &Torrent) -> Vec<String> {
+    if torrent.files.len() == 1 && !base.ends_with('/') {
+        return vec![base.to_string()];
+    }
+
+    let base = base.trim_end_matches('/');
+    torrent
+        .web_paths
+        .iter()
+        .map(|path| {
+            let mut url = base.to_string();
+            for p in path {
+                url.push('/');
+                url.extend(utf8_percent_encode(p, SEGMENT));
+            }
+            url
+        })
+        .collect()
+}
+
+// URL asking a seeding script for a piece (BEP 17)
+// ranges limits it to offset * length runs of the piece, the whole piece if empty
+pub fn script_url(base: &str, info_hash: &[u8], piece: u32, ranges: &[(u32, u32)]) -> String {
+    let sep = if base.contains('?') { '&' } else { '?' };
+    let mut url = format!(
+        "{}{}info_hash={}&piece={}",
+        base,
+        sep,
+        serialize_bytes(&info_hash.to_vec()),
+        piece
+    );
+    if !ranges.is_empty() {
+        let ranges: Vec<String> = ranges
+            .iter()
+            .map(|(s, len)| format!("{}-{}", s, s + len - 1))
+            .collect();
+        url.push_str("&ranges=");
+        url.push_str(&ranges.join(","));
+    }
+    url
+}
+
+// how a server hands out pieces
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SeedKind {
+    Files,  // serves the torrent's files (BEP 19 url-list)
+    Script, // serves pieces by index (BEP 17 httpseeds)
+}
+
+enum FetchError {
+    Failed,
+    RetryAfter(Duration), // server busy, asked us to come back later
+}
+
+// waits for the most wanted piece of work
+async fn next_piece(work: &Queue<Piece>, priorities: &Mutex<Priorities>) -> Piece {
+    loop {
+        let prio = priorities.lock().await;
+        if let Some(piece) = work.find_best(|x| prio.rank(x.1)).await {
+            return piece;
+        }
+        drop(prio);
+        work.wait().await;
+    }
+}
+
+// downloads pieces over HTTP from a web seed alongside the peer workers
+pub struct WebSeed {
+    id: u64,
+    kind: SeedKind,
+    base: String,
+    info_hash: Vec<u8>,
+    urls: Vec<String>,
+    lengths: Vec<usize>,
+    padding: Vec<bool>, // files that are all zeros and never on the server
+    piece_length: usize,
+    client: reqwest::Client,
+    work: Queue<Piece>,
+    priorities: Arc<Mutex<Priorities>>,
+    unfinished: Arc<Mutex<HashMap<u32, Downloading>>>,
+    hasher: Hasher,
+    brx: broadcast::Receiver<Op>,
+    tx: mpsc::Sender<Op>,
+    failures: u32, // requests failed in a row
+    stop: bool,
+}
+
+impl WebSeed {
+    pub fn from_client(
+        c: &Client,
+        i: u64,
+        kind: SeedKind,
+        url: &str,
+        brx: broadcast::Receiver<Op>,
+        tx: mpsc::Sender<Op>,
+    ) -> WebSeed {
+        WebSeed {
+            id: i,
+            kind,
+            base: url.to_string(),
+            info_hash: c.torrent.info_hash.clone(),
+            urls: file_urls(url, c.torrent),
+            lengths: c.torrent.files.iter().map(|f| f.length).collect(),
+            padding: c.torrent.files.iter().map(|f| f.is_padding()).collect(),
+            piece_length: c.torrent.piece_length as usize,
+            client: tracker_client(c.proxy.as_ref()),
+            work: c.torrent.pieces.clone(),
+            priorities: Arc::clone(&c.torrent.priorities),
+            unfinished: Arc::clone(&c.partial.unfinished),
+            hasher: c.partial.hasher.clone(),
+            brx,
+            tx,
+            failures: 0,
+            stop: false,
+        }
+    }
+
+    // fetches part of a file, accepting servers that ignore the range
+    async fn fetch_range(&self, file: usize, offset: usize, len: usize) -> Option<Vec<u8>> {
+        let range = format!("bytes={}-{}", offset, offset + len - 1);
+        let req = async {
+            let res = self
+                .client
+                .get(&self.urls[file])
+                .header(RANGE, range)
+                .send()
+                .await?;
+            Ok::<_, reqwest::Error>((res.status(), res.bytes().await?))
+        };
+        let (status, body) = tokio::time::timeout(WEBSEED_TIMEOUT, req)
+            .await
+            .ok()?
+            .ok()?;
+
+        match status {
+            StatusCode::PARTIAL_CONTENT if body.len() == len => Some(body.to_vec()),
+            StatusCode::OK if body.len() == self.lengths[file] => {
+                Some(body[offset..offset + len].to_vec())
+            }
+            _ => None,
+        }
+    }
+
+    // asks a seeding script for runs of a piece
+    // a busy script answers 503 with the seconds to wait as the body
+    async fn fetch_script(
+        &self,
+        piece: &Piece,
+        runs: &[(u32, u32)],
+    ) -> Result<Vec<u8>, FetchError> {
+        // a whole piece needs no ranges
+        let ranges = if runs == [(0, piece.2)] {
+            &[][..]
+        } else {
+            runs
+        };
+        let url = script_url(&self.base, &self.info_hash, piece.1, ranges);
+        let req = async {
+            let res = self.client.get(&url).send().await?;
+            Ok::<_, reqwest::Error>((res.status(), res.bytes().await?))
+        };
+        let (status, body) = match tokio::time::timeout(WEBSEED_TIMEOUT, req).await {
+            Ok(Ok(res)) => res,
+            _ => return Err(FetchError::Failed),
+        };
+
+        match status {
+            StatusCode::OK => Ok(body.to_vec()),
+            StatusCode::SERVICE_UNAVAILABLE => {
+                let secs = std::str::from_utf8(&body)
+                    .ok()
+                    .and_then(|b| b.trim().parse().ok());
+                Err(secs.map_or(FetchError::Failed, |s| {
+                    FetchError::RetryAfter(Duration::from_secs(s))
+                }))
+            }
+            _ => Err(FetchError::Failed),
+        }
+    }
+
+    // fetches offset * length runs of a piece, back to back
+    async fn fetch(&self, piece: &Piece, runs: &[(u32, u32)]) -> Result<Vec<u8>, FetchError> {
+        if self.kind == SeedKind::Script {
+            return self.fetch_script(piece, runs).await;
+        }
+
+        let mut buf = Vec::with_capacity(piece.2 as usize);
+        for (s, n) in runs {
+            let start = piece.1 as usize * self.piece_length + *s as usize;
+            let end = start + *n as usize;
+            for (file, offset, len) in file_ranges(&self.lengths, start, end) {
+                if self.padding[file] {
+                    buf.resize(buf.len() + len, 0);
+                    continue;
+                }
+                let res = self.fetch_range(file, offset, len).await;
+                buf.extend(res.ok_or(FetchError::Failed)?);
+            }
+        }
+        Ok(buf)
+    }
+
+    // doubles the retry delay with every failure in a row
+    fn backoff(&self) -> Duration {
+        let n = self.failures.saturating_sub(1).min(16);
+        (WEBSEED_RETRY * 2u32.pow(n)).min(WEBSEED_MAX_RETRY)
+    }
+
+    // waits out the delay unless told to stop
+    async fn wait(&mut self, delay: Duration) {
+        let deadline = tokio::time::Instant::now() + delay;
+        while !self.stop {
+            tokio::select! {
+                _ = tokio::time::delay_until(deadline) => break,
+                Ok(op) = self.brx.recv() => self.process_op(op),
+            }
+        }
+    }
+
+    fn process_op(&mut self, op: Op) {
+        if op.op_type == OpType::OpStop || op.op_type == OpType::OpDownStop {
+            self.stop = true;
+        }
+    }
+
+    async fn download_piece(&mut self, piece: Piece) {
+        // only fetch what a peer left behind
+        let d = self.unfinished.lock().await.remove(&piece.1);
+        let mut d = d.unwrap_or_else(|| Downloading::new(piece));
+        let runs = d.request_missing();
+
+        let res = match self.fetch(&piece, &runs).await {
+            Ok(buf) if d.receive_runs(&runs, &buf).is_some() => {
+                Ok(self.hasher.verify(piece, std::mem::take(&mut d.buf)).await)
+            }
+            Ok(_) => Err(FetchError::Failed),
+            Err(e) => Err(e),
+        };
+        match res {
+            Ok((true, buf)) => {
+                self.failures = 0;
+                let op = Op {
+                    id: self.id,
+                    op_type: OpType::OpPiece(piece.1, buf),
+                };
+                if self.tx.send(op).await.is_err() {
+                    self.stop = true;
+                }
+            }
+            res => {
+                if res.is_err() {
+                    // keep the blocks for whoever picks the piece up next
+                    d.cancel_requests();
+                    self.unfinished.lock().await.insert(piece.1, d);
+                }
+                self.work.push(piece).await;
+
+                let delay = match res {
+                    Err(FetchError::RetryAfter(d)) => {
+                        println!("Web seed {} busy, retrying in {:?}", self.base, d);
+                        d.min(WEBSEED_MAX_RETRY)
+                    }
+                    Ok(_) => {
+                        self.failures += 1;
+                        println!("Web seed {} sent bad data for piece {}", self.base, piece.1);
+                        self.backoff()
+                    }
+                    Err(FetchError::Failed) => {
+                        self.failures += 1;
+                        let delay = self.backoff();
+                        println!("Web seed {} failed, retrying in {:?}", self.base, delay);
+                        delay
+                    }
+                };
+                self.wait(delay).await;
+            }
+        }
+    }
+
+    pub async fn download(&mut self) {
+        while !self.stop {
+            tokio::select! {
+                piece = next_piece(&self.work, &self.priorities) => {
+                    self.download_piece(piece).await;
+                },
+                Ok(op) = self.brx.recv() => self.process_op(op),
+            }
+        }
+
+        println!("Web seed {} stopping", self.base);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::script_url;
+
+    #[test]
+    fn test_script_url() {
+        assert_eq!(
+            script_url("http://s.example/seed.php", &[0xab; 2], 7, &[]),
+            "http://s.example/seed.php?info_hash=%AB%AB&piece=7"
+        );
+        assert_eq!(
+            script_url("http://s.example/seed?id=1", b"a", 0, &[]),
+            "http://s.example/seed?id=1&info_hash=a&piece=0"
+        );
+        assert_eq!(
+            script_url("http://s.example/seed", b"a", 2, &[(0, 10), (30, 5)]),
+            "http://s.example/seed?info_hash=a&piece=2&ranges=0-9,30-34"
+        );
+    }
+}
@@ -1,16 +1,26 @@
 use crate::client;
 use crate::consts::*;
+use crate::downloading::Downloading;
+use crate::hasher::Hasher;
+use crate::ipfilter::IpFilter;
+use crate::messages::extended::{ExtendedHandshake, HANDSHAKE_ID};
 use crate::messages::handshake::Handshake;
 use crate::messages::messages::Message;
 use crate::messages::ops::*;
 use crate::opstream::OpStream;
 use crate::partial::Progress;
+use crate::priority::Priorities;
+use crate::proxy::Proxy;
+use crate::smartban::SmartBan;
+use crate::stats::{PeerStats, Stats};
 use crate::torrents::Piece;
 use crate::utils::bitfield::Bitfield;
-use crate::utils::calc_request;
 use crate::utils::queue::Queue;
-use std::cmp::min;
+use crate::utils::rate::Rate;
+use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::net::TcpStream;
 use tokio::prelude::*;
 use tokio::sync::{broadcast, mpsc, Mutex};
@@ -19,8 +29,15 @@ use tokio::time::timeout;
 pub struct Worker {
     id: u64,
     progress: Arc<Mutex<Progress>>,
-    peers: Queue<String>,
+    stats: Arc<Mutex<Stats>>,
+    peers: Queue<SocketAddr>,
     work: Queue<Piece>,
+    priorities: Arc<Mutex<Priorities>>,
+    unfinished: Arc<Mutex<HashMap<u32, Downloading>>>,
+    bans: Arc<Mutex<SmartBan>>,
+    hasher: Hasher,
+    filter: Arc<Mutex<IpFilter>>,
+    proxy: Option<Proxy>,
     handshake: Vec<u8>,
     info_hash: Vec<u8>,
     bf: Arc<Mutex<Bitfield>>,
@@ -31,10 +48,15 @@ pub struct Worker {
     mrx: mpsc::Receiver<Op>,      // individual receiver from client
     tx: mpsc::Sender<Op>,         // transmitter back to client
 
-    buf: Vec<u8>,
-    current: Option<Piece>,
-    requested: u32,
-    received: u32,
+    addr: Option<SocketAddr>,
+    downloading: Vec<Downloading>,
+    rate: Rate,
+    rtt: Option<Duration>,
+    reqq: u32, // max outstanding requests the peer accepts
+    extended: bool,
+    last_block: Instant, // last time a block arrived or requesting started
+    snubbed: bool,
+    snubbed_at: Instant,
     choked: bool,
     stop: bool,
 }
@@ -50,8 +72,15 @@ impl Worker {
         Worker {
             id: i,
             progress: Arc::clone(&c.partial.progress),
+            stats: Arc::clone(&c.stats),
             peers: c.peer_list.clone(),
             work: c.torrent.pieces.clone(),
+            priorities: Arc::clone(&c.torrent.priorities),
+            unfinished: Arc::clone(&c.partial.unfinished),
+            bans: Arc::clone(&c.bans),
+            hasher: c.partial.hasher.clone(),
+            filter: Arc::clone(&c.filter),
+            proxy: c.proxy.clone(),
             handshake: c.handshake.clone(),
             info_hash: c.torrent.info_hash.clone(),
             bf: c.partial.bf.clone(),
@@ -62,23 +91,35 @@ impl Worker {
             mrx,
             tx,
 
-            current: None,
-            requested: 0,
-            received: 0,
-            buf: Vec::new(),
+            addr: None,
+            downloading: Vec::new(),
+            rate: Rate::new(),
+            rtt: None,
+            reqq: MAXREQUESTS,
+            extended: false,
+            last_block: Instant::now(),
+            snubbed: false,
+            snubbed_at: Instant::now(),
             choked: true,
             stop: false,
         }
     }
 
-    // tries to connect to ip (with timeouts)
-    async fn connect(&self, ip: &str) -> Option<TcpStream> {
-        Some(timeout(TIMEOUT, TcpStream::connect(ip)).await.ok()?.ok()?)
+    // tries to connect to addr (with timeouts)
+    // goes through the proxy if there is one
+    async fn connect(&self, addr: SocketAddr) -> Option<TcpStream> {
+        let conn = async {
+            match &self.proxy {
+                Some(p) => p.connect(&addr.to_string()).await,
+                None => TcpStream::connect(addr).await,
+            }
+        };
+        timeout(TIMEOUT, conn).await.ok()?.ok()
     }
 
     // exchanges handshakes and exchanges bitfield with TcpStream
     // returns opposing bitfield if successful
-    async fn protocol(&mut self, mut s: TcpStream) -> Option<Bitfield> {
+    async fn protocol(&mut self, mut s: TcpStream, addr: Option<SocketAddr>) -> Option<Bitfield> {
         let mut buf = [0; 68];
         if self.disconnect {
             // inititor sends first handshake
@@ -89,7 +130,8 @@ impl Worker {
         }
         timeout(TIMEOUT, s.read(&mut buf)).await.ok()?.ok()?;
 
-        if Handshake::deserialize(buf.as_ref())?.info_hash != self.info_hash {
+        let handshake = Handshake::deserialize(buf.as_ref())?;
+        if handshake.info_hash != self.info_hash {
             return None;
         }
         if !self.disconnect {
@@ -99,6 +141,12 @@ impl Worker {
                 .ok()?;
         }
 
+        self.addr = addr;
+        self.extended = handshake.supports_extensions();
+        self.reqq = MAXREQUESTS;
+        self.rate = Rate::new();
+        self.rtt = None;
+        self.snubbed = false;
         self.stream = OpStream::from(s);
 
         // send own bitfield
@@ -112,7 +160,7 @@ impl Worker {
         }
 
         // get opposing bitfield
-        let response = self.stream.read_message().await;
+        let response = self.stream.read_message_timeout(TIMEOUT).await;
         let msg = response?;
         if let Message::Bitfield(bf) = msg {
             {
@@ -122,114 +170,286 @@ impl Worker {
                     return None;
                 }
             }
+            if self.extended {
+                let payload = ExtendedHandshake::new().serialize();
+                self.stream
+                    .send_message(Message::Extended(HANDSHAKE_ID, payload))
+                    .await?;
+            }
             Some(Bitfield::from(bf))
         } else {
             None
         }
     }
 
+    // returns true if the address is filtered or banned
+    async fn is_blocked(&self, addr: Option<SocketAddr>) -> bool {
+        match addr {
+            Some(addr) => {
+                self.filter.lock().await.is_blocked(&addr.ip())
+                    || self.bans.lock().await.is_banned(&addr.ip())
+            }
+            None => false,
+        }
+    }
+
     // attempts to connect to a peer
     // handshakes and interacts
-    async fn reach_peer(&mut self, ip: String) {
-        if let Some(peer) = self.connect(&ip).await {
-            println!(
-                "Worker {} attempting to connect to {:?}",
-                self.id,
-                peer.peer_addr().unwrap()
-            );
-            if let Some(bf) = self.protocol(peer).await {
+    async fn reach_peer(&mut self, addr: SocketAddr) {
+        if self.is_blocked(Some(addr)).await {
+            return;
+        }
+
+        if let Some(peer) = self.connect(addr).await {
+            println!("Worker {} attempting to connect to {}", self.id, addr);
+            if let Some(bf) = self.protocol(peer, Some(addr)).await {
                 self.interact(bf).await;
             }
         } else {
-            // put ip back
-            self.peers.push(ip).await;
+            // put addr back
+            self.peers.push(addr).await;
         }
     }
 
-    // if current is empty, attempts to find a piece of work
+    // attempts to find the most wanted piece of work the peer has
     // returns None if there aren't any
     async fn get_piece(&mut self, bf: &Bitfield) -> Option<()> {
-        if self.current == None {
-            self.current = self.work.find_first(|x| bf.has(x.1 as usize)).await;
-            self.requested = 0;
-            self.received = 0;
+        let prio = self.priorities.lock().await;
+        let piece = self
+            .work
+            .find_best(|x| prio.rank(x.1).filter(|_| bf.has(x.1 as usize)))
+            .await;
+        drop(prio);
+        let piece = piece?;
+
+        // pick up where another worker left off
+        let d = self.unfinished.lock().await.remove(&piece.1);
+        self.downloading
+            .push(d.unwrap_or_else(|| Downloading::new(piece)));
+
+        Some(())
+    }
+
+    // returns a piece to the work queue with its blocks
+    // so that another worker can finish it
+    // outstanding requests are cancelled if cancel is set
+    async fn hand_off(&mut self, mut d: Downloading, cancel: bool) -> Option<()> {
+        let pending = d.pending();
+        let piece = d.piece;
+
+        d.cancel_requests();
+        self.unfinished.lock().await.insert(piece.1, d);
+        if self.snubbed {
+            // won't be taken back by this worker, so expedite it
+            self.work.push_front(piece).await;
+        } else {
+            self.work.push(piece).await;
+        }
 
-            let piece = self.current.as_ref()?;
-            self.buf = vec![0; piece.2 as usize];
+        if cancel {
+            for (s, len) in pending {
+                self.stream
+                    .send_message(Message::Cancel(piece.1, s, len))
+                    .await?;
+            }
         }
 
         Some(())
     }
 
-    // sends requests and pieces and chooses new work piece if necessary
-    // returns true if requested and None if error
-    async fn manage_io(&mut self) -> Option<()> {
-        let piece = self.current.as_ref()?;
-        while self.requested < min(piece.2, self.received + MAXREQUESTS * BLOCKSIZE) {
-            let len = calc_request(self.requested, piece.2);
-            self.stream
-                .send_message(Message::Request(piece.1, self.requested, len))
-                .await?;
-            self.requested += len;
+    // gives up on requests that have been in flight too long and
+    // stops requesting from peers that have stopped sending data
+    // returns None if the connection should be dropped
+    async fn check_timeouts(&mut self) -> Option<()> {
+        if self.stream.since_received() > IDLE_TIMEOUT {
+            println!("Worker {} connection idle", self.id);
+            return None;
+        }
+        if self.stream.since_sent() > KEEPALIVE {
+            self.stream.send_message(Message::KeepAlive).await?;
+        }
+
+        if self.snubbed && self.snubbed_at.elapsed() > SNUB_RETRY {
+            // it may only have been busy, so give it another chance
+            println!("Worker {} retrying snubbing peer", self.id);
+            self.snubbed = false;
+            self.last_block = Instant::now();
+        }
+        if self.choked || self.snubbed || self.requests() == 0 {
+            return Some(());
+        }
+
+        if self.last_block.elapsed() > SNUB_TIMEOUT {
+            // keep the connection for uploading,
+            // but let other peers download the pieces
+            println!("Worker {} snubbed by peer", self.id);
+            self.snubbed = true;
+            self.snubbed_at = Instant::now();
+
+            let downloading: Vec<Downloading> = self.downloading.drain(..).collect();
+            for d in downloading {
+                self.hand_off(d, true).await?;
+            }
+            return Some(());
+        }
+
+        let t = self
+            .rtt
+            .map_or(REQUEST_TIMEOUT, |rtt| REQUEST_TIMEOUT.max(rtt * 4));
+        let (late, on_time): (Vec<Downloading>, Vec<Downloading>) =
+            self.downloading.drain(..).partition(|d| d.timed_out(t));
+        self.downloading = on_time;
+
+        for d in late {
+            println!("Worker {} timed out on piece {}", self.id, d.piece.1);
+            self.hand_off(d, true).await?;
         }
 
         Some(())
     }
 
-    // adds data into buf
-    // queues up new piece if necessary
-    async fn process_piece(&mut self, i: u32, s: u32, buf: Vec<u8>, bf: &Bitfield) -> Option<()> {
-        let piece = self.current.clone()?;
+    // number of requests in flight
+    fn requests(&self) -> u32 {
+        self.downloading.iter().map(|d| d.requests()).sum()
+    }
 
-        if i != piece.1 {
-            println!("Expecting {} but got {}.  Not correct piece", piece.1, i);
-            return None;
+    // number of requests to keep in flight, sized to the
+    // bandwidth-delay product of the connection and capped by the peer's reqq
+    fn queue_depth(&mut self) -> u32 {
+        if self.snubbed {
+            return 0;
         }
-        if s as usize + buf.len() > self.buf.len() {
-            println!("Too large payload!");
-            println!("{} + {} > {}", s, buf.len(), self.buf.len());
-            return None;
+
+        let rtt = self.rtt.map_or(0.0, |d| d.as_secs_f64());
+        let bytes = self.rate.get() * (QUEUE_TIME + rtt);
+        let n = (bytes / BLOCKSIZE as f64).ceil() as u32;
+
+        n.max(MINREQUESTS).min(self.reqq.min(MAXREQUESTS))
+    }
+
+    // smooths round trip samples the way TCP does
+    fn sample_rtt(&mut self, sample: Duration) {
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => (rtt * 7 + sample) / 8,
+            None => sample,
+        });
+    }
+
+    // publishes the state of the connection to the client's stats
+    async fn report(&mut self) {
+        let peer = PeerStats {
+            addr: self.addr,
+            rate: self.rate.get(),
+            rtt: self.rtt,
+            queue_depth: self.queue_depth(),
+            outstanding: self.downloading.iter().map(|d| d.outstanding()).sum(),
+            snubbed: self.snubbed,
+        };
+        self.stats.lock().await.peers.insert(self.id, peer);
+    }
+
+    // keeps the request pipeline full, picking up new pieces as necessary
+    // returns None if error
+    async fn manage_io(&mut self, bf: &Bitfield) -> Option<()> {
+        let depth = self.queue_depth();
+        if self.requests() == 0 {
+            // nothing was in flight, so the peer couldn't have been slow
+            self.last_block = Instant::now();
         }
 
-        self.buf[s as usize..s as usize + buf.len()].copy_from_slice(buf.as_slice());
-        self.received += buf.len() as u32;
+        while self.requests() < depth {
+            let next = self
+                .downloading
+                .iter_mut()
+                .find_map(|d| d.next_request().map(|(s, len)| (d.piece.1, s, len)));
+
+            if let Some((i, s, len)) = next {
+                self.stream
+                    .send_message(Message::Request(i, s, len))
+                    .await?;
+            } else if self.get_piece(bf).await.is_none() {
+                break;
+            }
+        }
 
-        if self.received >= piece.2 {
-            // received all of piece
-            self.received = 0;
-            self.current = None;
+        Some(())
+    }
 
-            if piece.verify(&self.buf) {
-                // verified piece
+    // adds block into the buffer of its piece
+    // sends piece off to client once complete
+    async fn process_piece(&mut self, i: u32, s: u32, buf: Vec<u8>, bf: &Bitfield) -> Option<()> {
+        self.last_block = Instant::now();
+        self.snubbed = false;
+
+        let pos = match self.downloading.iter().position(|d| d.piece.1 == i) {
+            Some(pos) => pos,
+            None => {
+                println!("Not expecting piece {}", i);
+                return Some(());
+            }
+        };
 
-                if self
-                    .tx
-                    .send(Op {
-                        id: self.id,
-                        op_type: OpType::OpPiece(i, self.buf.clone()),
-                    })
-                    .await
-                    .ok()
-                    == None
-                {
-                    println!("Couldn't send!");
+        match self.downloading[pos].receive(s, &buf, self.addr.map(|a| a.ip())) {
+            Some(rtt) => self.sample_rtt(rtt),
+            None => {
+                println!("Not expecting block {} of piece {}", s, i);
+                return Some(());
+            }
+        }
+        self.rate.add(buf.len());
+
+        if !self.downloading[pos].is_complete() {
+            return Some(());
+        }
+
+        // received all of piece
+        let mut d = self.downloading.remove(pos);
+        let (good, buf) = self
+            .hasher
+            .verify(d.piece, std::mem::take(&mut d.buf))
+            .await;
+        d.buf = buf;
+
+        if good {
+            // verified piece
+            for ip in self.bans.lock().await.piece_passed(&d) {
+                println!("Banning {} for sending bad data in piece {}", ip, i);
+            }
+
+            if self
+                .tx
+                .send(Op {
+                    id: self.id,
+                    op_type: OpType::OpPiece(i, d.buf),
+                })
+                .await
+                .is_err()
+            {
+                println!("Couldn't send!");
+                return None;
+            }
+
+            // get new piece
+            if self.downloading.is_empty() && self.get_piece(bf).await.is_none() {
+                // if no more pieces, send not interested
+                self.stream.send_message(Message::NotInterested).await?;
+                if self.disconnect {
                     return None;
                 }
+            }
+        } else {
+            // put piece back if doesn't match hash
+            self.work.push(d.piece).await;
+            println!("Couldn't verify!");
 
-                // get new piece
-                if self.get_piece(&bf).await == None {
-                    // if no more pieces, send not interested
-                    if self.stream.send_message(Message::NotInterested).await == None {
-                        return None;
-                    }
-                    if self.disconnect {
-                        return None;
-                    }
-                }
-            } else {
-                // put piece back if doesn't match hash
-                self.work.push(piece).await;
-                println!("Couldn't verify!");
+            let mut bans = self.bans.lock().await;
+            if let Some(ip) = bans.piece_failed(&d) {
+                println!("Banning {} after {} failed pieces", ip, bans.failures(&ip));
+            }
+            drop(bans);
+
+            // the piece may have been spoiled by someone else
+            if self.is_blocked(self.addr).await {
                 return None;
             }
         }
@@ -245,18 +465,26 @@ impl Worker {
             }
             Message::Unchoke => {
                 self.choked = false;
+                self.snubbed = false;
+                self.last_block = Instant::now();
             }
             Message::Choke => {
                 self.choked = true;
+                // peer discards pending requests when choking
+                for d in self.downloading.iter_mut() {
+                    d.cancel_requests();
+                }
             }
             Message::Have(i) => {
                 bf.add(i as usize);
 
-                if self.current == None {
-                    // check if interested again
-                    if self.get_piece(&bf).await != None {
-                        self.stream.send_message(Message::Interested).await?;
-                    }
+                // check if interested again
+                // a snubbed peer wouldn't be asked for the piece
+                if !self.snubbed
+                    && self.downloading.is_empty()
+                    && self.get_piece(bf).await.is_some()
+                {
+                    self.stream.send_message(Message::Interested).await?;
                 }
             }
             Message::Interested => {
@@ -264,7 +492,7 @@ impl Worker {
                 self.disconnect = false
             }
             Message::NotInterested => {
-                if self.current == None {
+                if self.downloading.is_empty() {
                     return None;
                 }
                 self.disconnect = true
@@ -278,6 +506,11 @@ impl Worker {
                     .await
                     .ok()?;
             }
+            Message::Extended(HANDSHAKE_ID, payload) => {
+                if let Some(reqq) = ExtendedHandshake::deserialize(&payload).and_then(|h| h.reqq) {
+                    self.reqq = reqq.max(1);
+                }
+            }
             _ => {}
         }
 
@@ -322,15 +555,21 @@ impl Worker {
     // assumes bitfields have been exchanges, but no current piece
     async fn interact(&mut self, mut bf: Bitfield) {
         self.choked = true;
+        let mut tick = tokio::time::interval(TICK);
 
         // find first piece
-        if self.get_piece(&bf).await == None && self.disconnect {
+        let interested = self.get_piece(&bf).await.is_some();
+        if !interested && self.disconnect {
             return;
         }
-        if self.current != None {
-            if self.stream.send_message(Message::Interested).await == None {
-                return;
-            }
+        if interested
+            && self
+                .stream
+                .send_message(Message::Interested)
+                .await
+                .is_none()
+        {
+            return;
         }
         println!("Worker {} connected", self.id);
 
@@ -351,33 +590,48 @@ impl Worker {
                         break
                     }
                 },
+                _ = tick.tick() => {
+                    if self.is_blocked(self.addr).await || self.check_timeouts().await.is_none() {
+                        break
+                    }
+                },
             }
 
-            // if not choked, send some requests
-            if !self.choked && self.manage_io().await == None {
+            // if not choked or snubbed, send some requests
+            if !self.choked && !self.snubbed && self.manage_io(&bf).await.is_none() {
                 println!("BAD4");
                 break;
             }
+            self.report().await;
         }
 
         println!("Worker {} disconnecting", self.id);
         self.stream.close();
+        self.stats.lock().await.peers.remove(&self.id);
+
         // if there is still work, return it to queue
-        if let Some(piece) = self.current.take() {
-            self.work.push(piece).await;
+        let downloading: Vec<Downloading> = self.downloading.drain(..).collect();
+        for d in downloading {
+            self.hand_off(d, false).await;
         }
     }
 
     // interacts with anyone trying to connect
-    pub async fn upload(&mut self, peer_q: Queue<TcpStream>, mut done: mpsc::Sender<()>) {
+    pub async fn upload(
+        &mut self,
+        peer_q: Queue<(TcpStream, SocketAddr)>,
+        mut done: mpsc::Sender<()>,
+    ) {
         while !self.stop {
             self.disconnect = false;
             tokio::select! {
-                peer = peer_q.pop_block() => {
-                    if let Ok(addr) = peer.peer_addr() {
-                        println!("Worker {} getting connection from {:?}", self.id, addr);
+                (peer, addr) = peer_q.pop_block() => {
+                    println!("Worker {} getting connection from {}", self.id, addr);
+                    if self.is_blocked(Some(addr)).await {
+                        done.send(()).await.ok();
+                        continue;
                     }
-                    if let Some(bf) = self.protocol(peer).await {
+                    if let Some(bf) = self.protocol(peer, Some(addr)).await {
                         self.interact(bf).await;
                         done.send(()).await.ok();
                     }
@@ -399,8 +653,8 @@ impl Worker {
         while !self.stop {
             self.disconnect = true;
             tokio::select! {
-                ip = self.peers.pop_block() => {
-                    self.reach_peer(ip).await;
+                addr = self.peers.pop_block() => {
+                    self.reach_peer(addr).await;
                 },
                 Ok(op) = self.brx.recv() => {
                     if op.op_type == OpType::OpStop ||  op.op_type == OpType::OpDownStop {
@@ -414,3 +668,93 @@ impl Worker {
         println!("Worker {} stopping", self.id);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Worker;
+    use crate::consts::SNUB_RETRY;
+    use crate::hasher::Hasher;
+    use crate::messages::messages::Message;
+    use crate::opstream::OpStream;
+    use crate::partial::Progress;
+    use crate::priority::Priorities;
+    use crate::torrents::Piece;
+    use crate::utils::bitfield::Bitfield;
+    use crate::utils::queue::Queue;
+    use crate::utils::rate::Rate;
+    use std::collections::{HashMap, VecDeque};
+    use std::sync::Arc;
+    use std::time::Instant;
+    use tokio::sync::{broadcast, mpsc, Mutex};
+
+    // a worker without a connection, downloading from a queue of n pieces
+    fn worker(n: u32) -> Worker {
+        let (tx, mrx) = mpsc::channel(1);
+        let (btx, brx) = broadcast::channel(1);
+        drop(btx);
+        let pieces: VecDeque<Piece> = (0..n).map(|i| Piece([0; 20], i, 10)).collect();
+
+        Worker {
+            id: 1,
+            progress: Arc::new(Mutex::new(Progress {
+                uploaded: 0,
+                downloaded: 0,
+                left: 10 * n as usize,
+            })),
+            stats: Arc::default(),
+            peers: Queue::new(),
+            work: Queue::from(pieces),
+            priorities: Arc::new(Mutex::new(Priorities::new(&[10 * n as usize], 10))),
+            unfinished: Arc::new(Mutex::new(HashMap::new())),
+            bans: Arc::default(),
+            hasher: Hasher::new(),
+            filter: Arc::default(),
+            proxy: None,
+            handshake: Vec::new(),
+            info_hash: Vec::new(),
+            bf: Arc::new(Mutex::new(Bitfield::new(1))),
+            disconnect: false,
+
+            stream: OpStream::new(),
+            brx,
+            mrx,
+            tx,
+
+            addr: None,
+            downloading: Vec::new(),
+            rate: Rate::new(),
+            rtt: None,
+            reqq: 1,
+            extended: false,
+            last_block: Instant::now(),
+            snubbed: false,
+            snubbed_at: Instant::now(),
+            choked: false,
+            stop: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_snub() {
+        let mut w = worker(2);
+        let mut bf = Bitfield::new(1);
+        w.snubbed = true;
+
+        // a have from a snubbing peer leaves the piece for others
+        w.process_msg(Some(Message::Have(0)), &mut bf)
+            .await
+            .unwrap();
+        assert!(w.downloading.is_empty());
+        assert_eq!(w.work.len().await, 2);
+
+        // after a while it is asked for work again, even without an unchoke
+        w.check_timeouts().await.unwrap();
+        assert!(w.snubbed);
+        w.snubbed_at = Instant::now() - 2 * SNUB_RETRY;
+        w.check_timeouts().await.unwrap();
+        assert!(!w.snubbed);
+        w.process_msg(Some(Message::Have(1)), &mut bf).await;
+        assert_eq!(w.downloading.len(), 1);
+        assert_eq!(w.work.len().await, 1);
+    }
+}
@@ -0,0 +1,113 @@
+// honors the file attributes of BEP 47: padding, executable and symlinks
+mod common;
+
+use common::*;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[test]
+fn test_attributes() {
+    let dir = temp_dir("attr");
+    let a = random_data(10_000);
+    let pad = vec![0; PIECE_LENGTH - a.len()];
+    let b = random_data(20_000);
+
+    let mut served = HashMap::new();
+    served.insert("/multi/a.bin".to_string(), a.clone());
+    served.insert("/multi/b.bin".to_string(), b.clone());
+
+    let requested = Arc::new(Mutex::new(Vec::new()));
+    let log = Arc::clone(&requested);
+    let port = http_server(move |req| {
+        log.lock().unwrap().push(req.path.clone());
+        match served.get(&req.path) {
+            Some(data) => range_response(req, data),
+            None => Response::new("404 Not Found", Vec::new()),
+        }
+    });
+
+    let file = |attr: &str, len: usize, path: &[&str]| {
+        B::Dict(vec![
+            ("attr", B::str(attr)),
+            ("length", B::Int(len as i64)),
+            ("path", B::List(path.iter().map(|p| B::str(p)).collect())),
+        ])
+    };
+    let link = B::Dict(vec![
+        ("attr", B::str("l")),
+        ("length", B::Int(0)),
+        ("path", B::List(vec![B::str("bin"), B::str("run")])),
+        ("symlink path", B::List(vec![B::str("a.bin")])),
+    ]);
+    let all: Vec<u8> = [&a[..], &pad, &b].concat();
+    let info = vec![
+        (
+            "files",
+            B::List(vec![
+                file("x", a.len(), &["a.bin"]),
+                file("p", pad.len(), &[".pad", &pad.len().to_string()]),
+                file("", b.len(), &["b.bin"]),
+                link,
+            ]),
+        ),
+        ("name", B::str("multi")),
+        ("piece length", B::Int(PIECE_LENGTH as i64)),
+        ("pieces", pieces(&all)),
+    ];
+    let torrent = B::Dict(vec![
+        ("announce", B::str("http://127.0.0.1:1/announce")),
+        ("info", B::Dict(info)),
+        ("url-list", B::str(&format!("http://127.0.0.1:{}/", port))),
+    ]);
+    let torrent = write_torrent(&dir, "multi", torrent);
+    let torrent = torrent.to_str().unwrap();
+
+    let out = run(&["files", torrent]);
+    let out = String::from_utf8_lossy(&out.stdout);
+    assert!(
+        out.contains("0: multi/a.bin (10000 bytes, executable)"),
+        "{}",
+        out
+    );
+    assert!(out.contains("bytes, padding)"), "{}", out);
+    assert!(
+        out.contains("3: multi/bin/run (0 bytes, link to ../a.bin)"),
+        "{}",
+        out
+    );
+
+    let out = dir.join("out");
+    let _leecher = spawn(&[
+        torrent,
+        "-d",
+        out.to_str().unwrap(),
+        "-p",
+        &free_port().to_string(),
+    ]);
+
+    let run = out.join("multi/bin/run");
+    let done = wait_for(Duration::from_secs(30), || run.exists());
+    assert!(done, "download did not complete");
+    assert_eq!(std::fs::read(&run).unwrap(), a);
+    assert_eq!(std::fs::read(out.join("multi/b.bin")).unwrap(), b);
+    assert!(!out.join("multi/.pad").exists());
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode = |p| std::fs::metadata(out.join(p)).unwrap().permissions().mode();
+        assert_eq!(mode("multi/a.bin") & 0o111, 0o111);
+        assert_eq!(mode("multi/b.bin") & 0o111, 0);
+    }
+
+    let requested = requested.lock().unwrap();
+    assert!(
+        requested.iter().all(|p| !p.contains(".pad")),
+        "{:?}",
+        requested
+    );
+
+    std::fs::remove_dir_all(&dir).ok();
+}
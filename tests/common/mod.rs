@@ -0,0 +1,339 @@
+// helpers for tests that run the ntorrent binary
+#![allow(dead_code)]
+
+use sha1::{Digest, Sha1};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Output, Stdio};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+const BIN: &str = env!("CARGO_BIN_EXE_ntorrent");
+
+// kills the process when the test ends, even on panic
+pub struct Proc(Child);
+
+impl Drop for Proc {
+    fn drop(&mut self) {
+        self.0.kill().ok();
+        self.0.wait().ok();
+    }
+}
+
+impl Proc {
+    // writes a line to the client's commands, if it was spawned with spawn_piped
+    pub fn send(&mut self, line: &str) {
+        let stdin = self.0.stdin.as_mut().expect("stdin is not piped");
+        writeln!(stdin, "{}", line).unwrap();
+    }
+}
+
+pub fn spawn(args: &[&str]) -> Proc {
+    spawn_with(args, Stdio::null())
+}
+
+// like spawn, but takes commands through send
+pub fn spawn_piped(args: &[&str]) -> Proc {
+    spawn_with(args, Stdio::piped())
+}
+
+fn spawn_with(args: &[&str], stdin: Stdio) -> Proc {
+    let child = Command::new(BIN)
+        .args(args)
+        .stdin(stdin)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Could not start ntorrent");
+    Proc(child)
+}
+
+pub fn run(args: &[&str]) -> Output {
+    Command::new(BIN).args(args).output().unwrap()
+}
+
+// sends a bare HTTP/1.0 request, returning the response or nothing on errors
+pub fn http_get(port: u16, path: &str) -> Vec<u8> {
+    let mut res = Vec::new();
+    if let Ok(mut s) = TcpStream::connect(("127.0.0.1", port)) {
+        let req = format!("GET {} HTTP/1.0\r\n\r\n", path);
+        s.write_all(req.as_bytes()).ok();
+        s.read_to_end(&mut res).ok();
+    }
+    res
+}
+
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("ntorrent_{}_{}", name, std::process::id()));
+    std::fs::remove_dir_all(&dir).ok();
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+pub fn random_data(len: usize) -> Vec<u8> {
+    (0..len).map(|_| rand::random()).collect()
+}
+
+// polls f until it returns true or the timeout passes
+pub fn wait_for(timeout: Duration, mut f: impl FnMut() -> bool) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        if f() {
+            return true;
+        }
+        sleep(Duration::from_millis(200));
+    }
+    false
+}
+
+// a bencoded value, for writing torrent files
+pub enum B {
+    Int(i64),
+    Str(Vec<u8>),
+    List(Vec<B>),
+    Dict(Vec<(&'static str, B)>),
+}
+
+impl B {
+    pub fn str(s: &str) -> B {
+        B::Str(s.as_bytes().to_vec())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            B::Int(i) => format!("i{}e", i).into_bytes(),
+            B::Str(s) => {
+                let mut res = format!("{}:", s.len()).into_bytes();
+                res.extend(s);
+                res
+            }
+            B::List(l) => {
+                let mut res = b"l".to_vec();
+                for x in l {
+                    res.extend(x.encode());
+                }
+                res.push(b'e');
+                res
+            }
+            B::Dict(d) => {
+                let mut d: Vec<&(&str, B)> = d.iter().collect();
+                d.sort_by_key(|(k, _)| *k);
+
+                let mut res = b"d".to_vec();
+                for (k, v) in d {
+                    res.extend(B::str(k).encode());
+                    res.extend(v.encode());
+                }
+                res.push(b'e');
+                res
+            }
+        }
+    }
+}
+
+pub const PIECE_LENGTH: usize = 1 << 15;
+
+// concatenated piece hashes of data
+pub fn pieces(data: &[u8]) -> B {
+    let mut res = Vec::new();
+    for chunk in data.chunks(PIECE_LENGTH) {
+        let mut hash = Sha1::new();
+        hash.input(chunk);
+        res.extend(hash.result().as_slice());
+    }
+    B::Str(res)
+}
+
+// info dictionary of a single file torrent
+pub fn single_info(name: &str, data: &[u8]) -> Vec<(&'static str, B)> {
+    vec![
+        ("length", B::Int(data.len() as i64)),
+        ("name", B::str(name)),
+        ("piece length", B::Int(PIECE_LENGTH as i64)),
+        ("pieces", pieces(data)),
+    ]
+}
+
+// info dictionary of a multi file torrent, files given as path * data
+pub fn multi_info(name: &str, files: &[(&[&str], &[u8])]) -> Vec<(&'static str, B)> {
+    let all: Vec<u8> = files.iter().flat_map(|(_, d)| d.iter().cloned()).collect();
+    let list = files
+        .iter()
+        .map(|(path, data)| {
+            B::Dict(vec![
+                ("length", B::Int(data.len() as i64)),
+                ("path", B::List(path.iter().map(|p| B::str(p)).collect())),
+            ])
+        })
+        .collect();
+
+    vec![
+        ("files", B::List(list)),
+        ("name", B::str(name)),
+        ("piece length", B::Int(PIECE_LENGTH as i64)),
+        ("pieces", pieces(&all)),
+    ]
+}
+
+pub fn write_torrent(dir: &Path, name: &str, torrent: B) -> PathBuf {
+    let path = dir.join(format!("{}.torrent", name));
+    std::fs::write(&path, torrent.encode()).unwrap();
+    path
+}
+
+// writes a single file torrent for data and returns its path
+pub fn make_torrent(dir: &Path, announce: &str, name: &str, data: &[u8]) -> PathBuf {
+    let torrent = B::Dict(vec![
+        ("announce", B::str(announce)),
+        ("info", B::Dict(single_info(name, data))),
+    ]);
+    write_torrent(dir, name, torrent)
+}
+
+pub struct Request {
+    pub path: String, // including the query
+    pub headers: Vec<(String, String)>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    // value of a query parameter, still percent encoded
+    pub fn param(&self, name: &str) -> Option<&str> {
+        let (_, query) = self.path.split_once('?')?;
+        query
+            .split('&')
+            .filter_map(|p| p.split_once('='))
+            .find(|(k, _)| *k == name)
+            .map(|(_, v)| v)
+    }
+
+    // first and last byte of a "bytes=a-b" range
+    pub fn range(&self) -> Option<(usize, usize)> {
+        let (a, b) = self
+            .header("range")?
+            .strip_prefix("bytes=")?
+            .split_once('-')?;
+        Some((a.parse().ok()?, b.parse().ok()?))
+    }
+}
+
+pub struct Response {
+    pub status: &'static str,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: &'static str, body: Vec<u8>) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body,
+        }
+    }
+}
+
+// sends a request and splits the response into its head and body
+pub fn http_request(port: u16, path: &str, headers: &[&str]) -> Option<(String, Vec<u8>)> {
+    let mut s = TcpStream::connect(("127.0.0.1", port)).ok()?;
+    let mut req = format!(
+        "GET {} HTTP/1.1\r\nHost: 127.0.0.1\r\nConnection: close\r\n",
+        path
+    );
+    for h in headers {
+        req.push_str(&format!("{}\r\n", h));
+    }
+    req.push_str("\r\n");
+    s.write_all(req.as_bytes()).ok()?;
+
+    let mut res = Vec::new();
+    s.read_to_end(&mut res).ok()?;
+    let i = res.windows(4).position(|w| w == b"\r\n\r\n")?;
+    let head = String::from_utf8_lossy(&res[..i]).into_owned();
+    Some((head, res[i + 4..].to_vec()))
+}
+
+// serves the requested range of data, or all of it
+pub fn range_response(req: &Request, data: &[u8]) -> Response {
+    match req.range() {
+        Some((s, e)) if e < data.len() => {
+            let mut res = Response::new("206 Partial Content", data[s..=e].to_vec());
+            res.headers.push((
+                "Content-Range".to_string(),
+                format!("bytes {}-{}/{}", s, e, data.len()),
+            ));
+            res
+        }
+        _ => Response::new("200 OK", data.to_vec()),
+    }
+}
+
+// serves HTTP/1.0 style requests, one connection per request,
+// on a background thread and returns the port
+pub fn http_server<F>(mut handler: F) -> u16
+where
+    F: FnMut(&Request) -> Response + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    std::thread::spawn(move || {
+        for s in listener.incoming() {
+            let mut s = match s {
+                Ok(s) => s,
+                Err(_) => continue,
+            };
+
+            let mut head = Vec::new();
+            let mut b = [0; 1];
+            while !head.ends_with(b"\r\n\r\n") {
+                if s.read_exact(&mut b).is_err() {
+                    break;
+                }
+                head.push(b[0]);
+            }
+
+            let head = String::from_utf8_lossy(&head);
+            let mut lines = head.lines();
+            let path = lines
+                .next()
+                .and_then(|l| l.split_whitespace().nth(1))
+                .unwrap_or("/")
+                .to_string();
+            let headers = lines
+                .filter_map(|l| l.split_once(':'))
+                .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+                .collect();
+
+            let res = handler(&Request { path, headers });
+            let mut out = format!(
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+                res.status,
+                res.body.len()
+            );
+            for (k, v) in res.headers {
+                out.push_str(&format!("{}: {}\r\n", k, v));
+            }
+            out.push_str("\r\n");
+            s.write_all(out.as_bytes()).ok();
+            s.write_all(&res.body).ok();
+        }
+    });
+
+    port
+}
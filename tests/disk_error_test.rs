@@ -0,0 +1,68 @@
+// pauses on disk errors and picks up again without losing pieces
+mod common;
+
+use common::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::Duration;
+
+#[test]
+fn test_disk_error() {
+    let dir = temp_dir("disk_error");
+    let data = random_data(3 * PIECE_LENGTH + 100);
+
+    let requests = Arc::new(AtomicUsize::new(0));
+    let count = Arc::clone(&requests);
+    let served = data.clone();
+    let seed_port = http_server(move |req| {
+        count.fetch_add(1, Ordering::SeqCst);
+        match req.param("piece").and_then(|p| p.parse::<usize>().ok()) {
+            Some(i) if i * PIECE_LENGTH < served.len() => {
+                let end = served.len().min((i + 1) * PIECE_LENGTH);
+                Response::new("200 OK", served[i * PIECE_LENGTH..end].to_vec())
+            }
+            _ => Response::new("404 Not Found", Vec::new()),
+        }
+    });
+
+    let torrent = B::Dict(vec![
+        ("announce", B::str("http://127.0.0.1:1/announce")),
+        (
+            "httpseeds",
+            B::List(vec![B::str(&format!("http://127.0.0.1:{}/", seed_port))]),
+        ),
+        ("info", B::Dict(single_info("data.bin", &data))),
+    ]);
+    let torrent = write_torrent(&dir, "data", torrent);
+
+    // nothing can be written where the file should go
+    let out = dir.join("out");
+    std::fs::create_dir_all(out.join("data.bin")).unwrap();
+    let mut client = spawn_piped(&[
+        torrent.to_str().unwrap(),
+        "-d",
+        out.to_str().unwrap(),
+        "--allocate",
+        "none",
+        "-p",
+        &free_port().to_string(),
+    ]);
+    let started = wait_for(Duration::from_secs(10), || {
+        requests.load(Ordering::SeqCst) > 0
+    });
+    assert!(started, "nothing was fetched");
+    sleep(Duration::from_secs(1));
+    assert!(out.join("data.bin").is_dir());
+
+    std::fs::remove_dir(out.join("data.bin")).unwrap();
+    client.send("resume");
+    let done = wait_for(Duration::from_secs(30), || {
+        std::fs::read(out.join("data.bin")).ok().as_ref() == Some(&data)
+    });
+    assert!(done, "download did not complete");
+    // pieces that arrived while paused were kept
+    assert_eq!(requests.load(Ordering::SeqCst), 4);
+
+    std::fs::remove_dir_all(&dir).ok();
+}
@@ -0,0 +1,119 @@
+// downloads some of the files of a torrent from a web seed
+mod common;
+
+use common::*;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[test]
+fn test_only() {
+    let dir = temp_dir("only");
+    // b shares its first piece with a and its last with c
+    let a = random_data(40_000);
+    let b = random_data(30_000);
+    let c = random_data(50_000);
+
+    let mut served = HashMap::new();
+    served.insert("/multi/a.bin".to_string(), a.clone());
+    served.insert("/multi/b.bin".to_string(), b.clone());
+    served.insert("/multi/c.bin".to_string(), c.clone());
+
+    let requested = Arc::new(Mutex::new(Vec::new()));
+    let log = Arc::clone(&requested);
+    let port = http_server(move |req| {
+        log.lock().unwrap().push(req.path.clone());
+        match served.get(&req.path) {
+            Some(data) => range_response(req, data),
+            None => Response::new("404 Not Found", Vec::new()),
+        }
+    });
+
+    let info = multi_info(
+        "multi",
+        &[(&["a.bin"], &a), (&["b.bin"], &b), (&["c.bin"], &c)],
+    );
+    let torrent = B::Dict(vec![
+        ("announce", B::str("http://127.0.0.1:1/announce")),
+        ("info", B::Dict(info)),
+        ("url-list", B::str(&format!("http://127.0.0.1:{}/", port))),
+    ]);
+    let torrent = write_torrent(&dir, "multi", torrent);
+    let torrent = torrent.to_str().unwrap();
+
+    let out = run(&["files", torrent]);
+    let out = String::from_utf8_lossy(&out.stdout);
+    assert!(out.contains("1: multi/b.bin (30000 bytes)"), "{}", out);
+
+    let out = dir.join("out");
+    let _leecher = spawn(&[
+        torrent,
+        "-d",
+        out.to_str().unwrap(),
+        "-p",
+        &free_port().to_string(),
+        "--only",
+        "1",
+    ]);
+
+    let done = wait_for(Duration::from_secs(30), || {
+        std::fs::read(out.join("multi/b.bin")).ok().as_ref() == Some(&b)
+    });
+    assert!(done, "download did not complete");
+    assert!(!out.join("multi/a.bin").exists());
+    assert!(!out.join("multi/c.bin").exists());
+
+    // only the two pieces overlapping b were fetched, each spanning two files
+    assert_eq!(requested.lock().unwrap().len(), 4);
+
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn test_sequential() {
+    let dir = temp_dir("sequential");
+    let data = random_data(5 * PIECE_LENGTH - 100);
+
+    let requested = Arc::new(Mutex::new(Vec::new()));
+    let log = Arc::clone(&requested);
+    let served = data.clone();
+    let port = http_server(move |req| {
+        if let Some((s, _)) = req.range() {
+            log.lock().unwrap().push(s / PIECE_LENGTH);
+        }
+        range_response(req, &served)
+    });
+
+    let torrent = B::Dict(vec![
+        ("announce", B::str("http://127.0.0.1:1/announce")),
+        ("info", B::Dict(single_info("data.bin", &data))),
+        (
+            "url-list",
+            B::str(&format!("http://127.0.0.1:{}/data.bin", port)),
+        ),
+    ]);
+    let torrent = write_torrent(&dir, "data", torrent);
+
+    let out = dir.join("out");
+    let start = format!("0={}", 2 * PIECE_LENGTH + 10);
+    let _leecher = spawn(&[
+        torrent.to_str().unwrap(),
+        "-d",
+        out.to_str().unwrap(),
+        "-p",
+        &free_port().to_string(),
+        "--sequential",
+        "--start",
+        &start,
+    ]);
+
+    let done = wait_for(Duration::from_secs(30), || {
+        std::fs::read(out.join("data.bin")).ok().as_ref() == Some(&data)
+    });
+    assert!(done, "download did not complete");
+
+    // in order from the start, then wrapping around
+    assert_eq!(*requested.lock().unwrap(), vec![2, 3, 4, 0, 1]);
+
+    std::fs::remove_dir_all(&dir).ok();
+}
@@ -0,0 +1,77 @@
+// moves finished downloads to the complete directory, and elsewhere on request
+mod common;
+
+use common::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::Duration;
+
+#[test]
+fn test_move() {
+    let dir = temp_dir("move");
+    let data = random_data(3 * PIECE_LENGTH + 100);
+
+    let requests = Arc::new(AtomicUsize::new(0));
+    let count = Arc::clone(&requests);
+    let served = data.clone();
+    let seed_port = http_server(move |req| {
+        count.fetch_add(1, Ordering::SeqCst);
+        match req.param("piece").and_then(|p| p.parse::<usize>().ok()) {
+            Some(i) if i * PIECE_LENGTH < served.len() => {
+                let end = served.len().min((i + 1) * PIECE_LENGTH);
+                Response::new("200 OK", served[i * PIECE_LENGTH..end].to_vec())
+            }
+            _ => Response::new("404 Not Found", Vec::new()),
+        }
+    });
+
+    let torrent = B::Dict(vec![
+        ("announce", B::str("http://127.0.0.1:1/announce")),
+        (
+            "httpseeds",
+            B::List(vec![B::str(&format!("http://127.0.0.1:{}/", seed_port))]),
+        ),
+        ("info", B::Dict(single_info("data.bin", &data))),
+    ]);
+    let torrent = write_torrent(&dir, "data", torrent);
+    let (inc, done) = (dir.join("inc"), dir.join("done"));
+    let args = [
+        torrent.to_str().unwrap(),
+        "-d",
+        inc.to_str().unwrap(),
+        "--complete",
+        done.to_str().unwrap(),
+        "-p",
+        &free_port().to_string(),
+    ];
+
+    let client = spawn(&args);
+    let moved = wait_for(Duration::from_secs(30), || {
+        std::fs::read(done.join("data.bin")).ok().as_ref() == Some(&data)
+            && done.join("data.bin.resume").exists()
+    });
+    assert!(moved, "download was not moved");
+    assert!(!inc.join("data.bin").exists());
+    assert!(!inc.join("data.bin.resume").exists());
+    drop(client);
+
+    // picked up from the complete directory without fetching anything
+    let fetched = requests.load(Ordering::SeqCst);
+    let mut client = spawn_piped(&args);
+    sleep(Duration::from_secs(2));
+    assert_eq!(requests.load(Ordering::SeqCst), fetched);
+    assert!(!inc.join("data.bin").exists());
+
+    // and moved again while seeding
+    let other = dir.join("other");
+    client.send(&format!("move {}", other.display()));
+    let moved = wait_for(Duration::from_secs(10), || {
+        std::fs::read(other.join("data.bin")).ok().as_ref() == Some(&data)
+            && other.join("data.bin.resume").exists()
+    });
+    assert!(moved, "download was not moved while seeding");
+    assert!(!done.join("data.bin").exists());
+
+    std::fs::remove_dir_all(&dir).ok();
+}
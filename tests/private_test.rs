@@ -0,0 +1,147 @@
+// a private torrent (BEP 27) moved to a new tracker starts over with it
+mod common;
+
+use common::*;
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// a tracker that logs announces and hands out peers
+fn tracker(peers: Vec<u8>, log: Arc<Mutex<Vec<Request>>>) -> u16 {
+    http_server(move |req| {
+        if !req.path.starts_with("/announce") {
+            return Response::new("404 Not Found", Vec::new());
+        }
+        log.lock().unwrap().push(Request {
+            path: req.path.clone(),
+            headers: Vec::new(),
+        });
+
+        let mut body = format!("d8:intervali1800e5:peers{}:", peers.len()).into_bytes();
+        body.extend(&peers);
+        body.extend(b"10:tracker id3:abce");
+        Response::new("200 OK", body)
+    })
+}
+
+#[test]
+fn test_private_tracker_change() {
+    let dir = temp_dir("private");
+    let data = random_data(2 * PIECE_LENGTH);
+
+    // peers that only count who connects
+    let listener = TcpListener::bind("0.0.0.0:0").unwrap();
+    let peer_port = listener.local_addr().unwrap().port();
+    let connects = Arc::new(AtomicUsize::new(0));
+    let count = Arc::clone(&connects);
+    std::thread::spawn(move || {
+        let mut held = Vec::new();
+        for s in listener.incoming() {
+            count.fetch_add(1, Ordering::SeqCst);
+            held.push(s);
+        }
+    });
+    // more than there are downloaders, so some are left to save
+    let mut peers = Vec::new();
+    for i in 1..=30 {
+        peers.extend(&[127, 0, 0, i]);
+        peers.extend(&peer_port.to_be_bytes());
+    }
+
+    let old_log = Arc::new(Mutex::new(Vec::new()));
+    let old = tracker(peers, Arc::clone(&old_log));
+    let new_log = Arc::new(Mutex::new(Vec::new()));
+    let new = tracker(Vec::new(), Arc::clone(&new_log));
+
+    // pieces are only handed out once the tracker knows us,
+    // so its tracker id makes it into the resume file
+    let announced = Arc::new(AtomicBool::new(false));
+    let ready = Arc::clone(&announced);
+    let log = Arc::clone(&old_log);
+    let served = data.clone();
+    let seed = http_server(move |req| {
+        if log.lock().unwrap().is_empty() {
+            return Response::new("503 Service Unavailable", b"1".to_vec());
+        }
+        ready.store(true, Ordering::SeqCst);
+        match req.param("piece").and_then(|p| p.parse::<usize>().ok()) {
+            Some(i) if i < 2 => Response::new(
+                "200 OK",
+                served[i * PIECE_LENGTH..(i + 1) * PIECE_LENGTH].to_vec(),
+            ),
+            _ => Response::new("404 Not Found", Vec::new()),
+        }
+    });
+
+    let write = |name: &str, port: u16| {
+        let mut info = single_info("data.bin", &data);
+        info.push(("private", B::Int(1)));
+        let torrent = B::Dict(vec![
+            (
+                "announce",
+                B::str(&format!("http://127.0.0.1:{}/announce", port)),
+            ),
+            (
+                "httpseeds",
+                B::List(vec![B::str(&format!("http://127.0.0.1:{}/", seed))]),
+            ),
+            ("info", B::Dict(info)),
+        ]);
+        write_torrent(&dir, name, torrent)
+    };
+    let before = write("before", old);
+    let after = write("after", new);
+
+    let out = dir.join("out");
+    let resume = out.join("data.bin.resume");
+    let peer = format!(":{}", peer_port);
+    {
+        let _client = spawn(&[
+            before.to_str().unwrap(),
+            "-d",
+            out.to_str().unwrap(),
+            "-p",
+            &free_port().to_string(),
+        ]);
+        let done = wait_for(Duration::from_secs(30), || {
+            let r = std::fs::read(&resume).unwrap_or_default();
+            let r = String::from_utf8_lossy(&r);
+            std::fs::read(out.join("data.bin")).ok().as_ref() == Some(&data)
+                && r.contains("3:abc")
+                && r.contains(&peer)
+        });
+        assert!(done, "download did not complete");
+    }
+    assert!(announced.load(Ordering::SeqCst));
+    let key = old_log.lock().unwrap()[0].param("key").unwrap().to_string();
+    let old_announces = old_log.lock().unwrap().len();
+    connects.store(0, Ordering::SeqCst);
+
+    // same info hash, new announce URL as when a passkey changes
+    // the data is gone, so there is downloading to do
+    std::fs::remove_file(out.join("data.bin")).unwrap();
+    let _client = spawn(&[
+        after.to_str().unwrap(),
+        "-d",
+        out.to_str().unwrap(),
+        "-p",
+        &free_port().to_string(),
+    ]);
+    let started = wait_for(Duration::from_secs(20), || {
+        !new_log.lock().unwrap().is_empty()
+    });
+    assert!(started, "new tracker never announced to");
+    std::thread::sleep(Duration::from_secs(2));
+
+    let new_log = new_log.lock().unwrap();
+    let first = &new_log[0];
+    assert_eq!(first.param("event"), Some("started"));
+    assert_eq!(first.param("trackerid"), None);
+    assert_ne!(first.param("key"), Some(key.as_str()));
+    assert_eq!(old_log.lock().unwrap().len(), old_announces);
+    // the saved peers came from the old tracker
+    assert_eq!(connects.load(Ordering::SeqCst), 0);
+
+    std::fs::remove_dir_all(&dir).ok();
+}
@@ -0,0 +1,150 @@
+// restarts a finished download from its fast-resume file
+mod common;
+
+use common::*;
+use std::fs::{File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// overwrites the first byte of a file, keeping its mtime if asked
+fn corrupt(path: &Path, keep_mtime: bool) {
+    let mtime = std::fs::metadata(path).unwrap().modified().unwrap();
+    let mut f = OpenOptions::new().write(true).open(path).unwrap();
+    f.seek(SeekFrom::Start(0)).unwrap();
+    f.write_all(b"!").unwrap();
+
+    let mtime = if keep_mtime {
+        mtime
+    } else {
+        mtime + Duration::from_secs(10)
+    };
+    File::options()
+        .write(true)
+        .open(path)
+        .unwrap()
+        .set_modified(mtime)
+        .unwrap();
+}
+
+// reads the file over HTTP from a client serving out
+fn serve(torrent: &str, out: &Path) -> Vec<u8> {
+    let port = free_port();
+    let _client = spawn(&[
+        "serve-http",
+        torrent,
+        "-d",
+        out.to_str().unwrap(),
+        "-p",
+        &free_port().to_string(),
+        "--http-port",
+        &port.to_string(),
+    ]);
+
+    let mut res = None;
+    wait_for(Duration::from_secs(10), || {
+        res = http_request(port, "/data.bin", &[]);
+        res.is_some()
+    });
+    res.expect("server never started").1
+}
+
+#[test]
+fn test_fast_resume() {
+    let dir = temp_dir("fast_resume");
+    let mut data = random_data(3 * PIECE_LENGTH + 100);
+
+    let requests = Arc::new(AtomicUsize::new(0));
+    let count = Arc::clone(&requests);
+    let served = data.clone();
+    let seed_port = http_server(move |req| {
+        count.fetch_add(1, Ordering::SeqCst);
+        match req.param("piece").and_then(|p| p.parse::<usize>().ok()) {
+            Some(i) if i * PIECE_LENGTH < served.len() => {
+                let end = served.len().min((i + 1) * PIECE_LENGTH);
+                Response::new("200 OK", served[i * PIECE_LENGTH..end].to_vec())
+            }
+            _ => Response::new("404 Not Found", Vec::new()),
+        }
+    });
+
+    let torrent = B::Dict(vec![
+        ("announce", B::str("http://127.0.0.1:1/announce")),
+        (
+            "httpseeds",
+            B::List(vec![B::str(&format!("http://127.0.0.1:{}/", seed_port))]),
+        ),
+        ("info", B::Dict(single_info("data.bin", &data))),
+    ]);
+    let torrent = write_torrent(&dir, "data", torrent);
+    let torrent = torrent.to_str().unwrap();
+
+    let out = dir.join("out");
+    let file = out.join("data.bin");
+    {
+        let _client = spawn(&[
+            torrent,
+            "-d",
+            out.to_str().unwrap(),
+            "-p",
+            &free_port().to_string(),
+        ]);
+        let done = wait_for(Duration::from_secs(30), || {
+            std::fs::read(&file).ok().as_ref() == Some(&data)
+                && out.join("data.bin.resume").exists()
+        });
+        assert!(done, "download did not complete");
+    }
+    // pieces were written in place, not kept on the side
+    assert!(!out.join("data.bin.part").exists());
+    let fetched = requests.load(Ordering::SeqCst);
+    assert_eq!(fetched, 4);
+
+    // untouched as far as the mtime goes, so nothing is checked
+    let good = data.clone();
+    corrupt(&file, true);
+    data[0] = b'!';
+    assert!(serve(torrent, &out) == data, "files were checked again");
+    assert_eq!(requests.load(Ordering::SeqCst), fetched);
+
+    // a changed mtime has the pieces checked and the bad one fetched again
+    corrupt(&file, false);
+    assert!(
+        serve(torrent, &out) == good,
+        "corrupt piece was not fetched again"
+    );
+    assert_eq!(requests.load(Ordering::SeqCst), fetched + 1);
+
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn test_allocation() {
+    let dir = temp_dir("allocation");
+    let data = random_data(3 * PIECE_LENGTH + 100);
+    let torrent = make_torrent(&dir, "http://127.0.0.1:1/announce", "data.bin", &data);
+
+    let out = dir.join("out");
+    let file = out.join("data.bin");
+    let _client = spawn(&[
+        torrent.to_str().unwrap(),
+        "-d",
+        out.to_str().unwrap(),
+        "-p",
+        &free_port().to_string(),
+        "--allocate",
+        "full",
+    ]);
+
+    // laid out before any piece arrives, and the mode is remembered
+    let allocated = wait_for(Duration::from_secs(10), || {
+        let resume = std::fs::read(out.join("data.bin.resume")).unwrap_or_default();
+        std::fs::metadata(&file).is_ok_and(|m| m.len() == data.len() as u64)
+            && resume.windows(19).any(|w| w == b"10:allocation4:full")
+    });
+    assert!(allocated, "file was not allocated");
+
+    std::fs::remove_dir_all(&dir).ok();
+}
@@ -0,0 +1,158 @@
+// hostile file paths never leave the download directory
+mod common;
+
+use common::*;
+use std::path::Path;
+use std::time::Duration;
+
+fn file(path: &[&[u8]], data: &[u8]) -> B {
+    B::Dict(vec![
+        ("length", B::Int(data.len() as i64)),
+        (
+            "path",
+            B::List(path.iter().map(|p| B::Str(p.to_vec())).collect()),
+        ),
+    ])
+}
+
+// every file under dir, relative to it
+fn walk(dir: &Path, root: &Path, res: &mut Vec<String>) {
+    for e in std::fs::read_dir(dir).unwrap() {
+        let path = e.unwrap().path();
+        if path.is_dir() {
+            walk(&path, root, res);
+        } else {
+            let rel = path.strip_prefix(root).unwrap();
+            res.push(rel.to_string_lossy().into_owned());
+        }
+    }
+}
+
+#[test]
+fn test_hostile_paths() {
+    let dir = temp_dir("sanitize");
+    let long = format!("{}.bin", "x".repeat(300));
+    // each file's URL holds a different needle, see the server below
+    let files: Vec<(Vec<&[u8]>, Vec<u8>)> = vec![
+        (vec![b"..", b"..", b"escape.bin"], random_data(20_000)),
+        (vec![b"/tmp", b"abs.bin"], random_data(3_000)),
+        (vec![b"CON"], random_data(100)),
+        (vec![b"Readme.txt"], random_data(50)),
+        (vec![b"README.TXT"], random_data(60)),
+        (vec![b"bad\xff.bin"], random_data(70)),
+        (vec![long.as_bytes()], random_data(80)),
+    ];
+    let needles = ["escape", "abs", "CON", "Readme", "README", "bad", "xxxx"];
+
+    let served: Vec<(&str, Vec<u8>)> = needles
+        .iter()
+        .cloned()
+        .zip(files.iter().map(|f| f.1.clone()))
+        .collect();
+    let port = http_server(
+        move |req| match served.iter().find(|(n, _)| req.path.contains(n)) {
+            Some((_, data)) => range_response(req, data),
+            None => Response::new("404 Not Found", Vec::new()),
+        },
+    );
+
+    let all: Vec<u8> = files.iter().flat_map(|f| f.1.iter().cloned()).collect();
+    let info = vec![
+        (
+            "files",
+            B::List(files.iter().map(|(p, d)| file(p, d)).collect()),
+        ),
+        ("name", B::str("../evil")),
+        ("piece length", B::Int(PIECE_LENGTH as i64)),
+        ("pieces", pieces(&all)),
+    ];
+    let torrent = B::Dict(vec![
+        ("announce", B::str("http://127.0.0.1:1/announce")),
+        ("info", B::Dict(info)),
+        ("url-list", B::str(&format!("http://127.0.0.1:{}/", port))),
+    ]);
+    let torrent = write_torrent(&dir, "evil", torrent);
+    let torrent = torrent.to_str().unwrap();
+
+    let out = run(&["files", torrent]);
+    let out = String::from_utf8_lossy(&out.stdout);
+    assert!(
+        out.contains("0: .._evil/escape.bin (20000 bytes)"),
+        "{}",
+        out
+    );
+    assert!(out.contains("1: .._evil/_tmp/abs.bin"), "{}", out);
+    assert!(out.contains("2: .._evil/_CON"), "{}", out);
+    assert!(out.contains("4: .._evil/README_1.TXT"), "{}", out);
+    assert!(out.contains("5: .._evil/bad\u{fffd}.bin"), "{}", out);
+
+    let out = dir.join("out");
+    let _leecher = spawn(&[
+        torrent,
+        "-d",
+        out.to_str().unwrap(),
+        "-p",
+        &free_port().to_string(),
+    ]);
+
+    let root = out.join(".._evil");
+    let last = root.join(format!("{}.bin", "x".repeat(251)));
+    let done = wait_for(Duration::from_secs(30), || {
+        std::fs::read(&last).ok().as_ref() == Some(&files[6].1)
+    });
+    assert!(done, "download did not complete");
+
+    let expected = [
+        "escape.bin",
+        "_tmp/abs.bin",
+        "_CON",
+        "Readme.txt",
+        "README_1.TXT",
+        "bad\u{fffd}.bin",
+    ];
+    for (name, (_, data)) in expected.iter().zip(files.iter()) {
+        assert_eq!(&std::fs::read(root.join(name)).unwrap(), data, "{}", name);
+    }
+
+    // nothing outside the download directory, and only the files inside it
+    let mut found = Vec::new();
+    walk(&dir, &dir, &mut found);
+    found.sort();
+    assert_eq!(found.len(), 9, "{:?}", found);
+    assert!(found
+        .iter()
+        .all(|f| f.starts_with("out/") || f == "evil.torrent"));
+    assert!(
+        found.contains(&"out/.._evil.resume".to_string()),
+        "{:?}",
+        found
+    );
+
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn test_empty_path() {
+    let dir = temp_dir("sanitize_empty");
+    let data = random_data(100);
+    let info = vec![
+        ("files", B::List(vec![file(&[b"..", b"."], &data)])),
+        ("name", B::str("empty")),
+        ("piece length", B::Int(PIECE_LENGTH as i64)),
+        ("pieces", pieces(&data)),
+    ];
+    let torrent = B::Dict(vec![
+        ("announce", B::str("http://127.0.0.1:1/announce")),
+        ("info", B::Dict(info)),
+    ]);
+    let torrent = write_torrent(&dir, "empty", torrent);
+
+    // refused outright, as there is nowhere safe to put it
+    let out = run(&["files", torrent.to_str().unwrap()]);
+    assert!(!out.status.success());
+    let err = String::from_utf8_lossy(&out.stderr);
+    assert!(err.contains("File 0 with no path in torrent!"), "{}", err);
+    assert!(!err.contains("panicked"), "{}", err);
+
+    std::fs::remove_dir_all(&dir).ok();
+}
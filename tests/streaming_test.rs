@@ -0,0 +1,82 @@
+// reads the files of a torrent over HTTP while it downloads
+mod common;
+
+use common::*;
+use std::time::Duration;
+
+#[test]
+fn test_serve_http() {
+    let dir = temp_dir("serve_http");
+    let a = random_data(40_000);
+    let b = random_data(70_000);
+    let all = [a.clone(), b.clone()].concat();
+
+    // the seed is busy at first, so the reads have to wait for pieces
+    let mut busy = true;
+    let seed_port = http_server(move |req| {
+        if busy {
+            busy = false;
+            return Response::new("503 Service Unavailable", b"1".to_vec());
+        }
+        match req.param("piece").and_then(|p| p.parse::<usize>().ok()) {
+            Some(i) if i * PIECE_LENGTH < all.len() => {
+                let end = all.len().min((i + 1) * PIECE_LENGTH);
+                Response::new("200 OK", all[i * PIECE_LENGTH..end].to_vec())
+            }
+            _ => Response::new("404 Not Found", Vec::new()),
+        }
+    });
+
+    let info = multi_info("multi", &[(&["a.bin"], &a), (&["sub dir", "b.bin"], &b)]);
+    let torrent = B::Dict(vec![
+        ("announce", B::str("http://127.0.0.1:1/announce")),
+        (
+            "httpseeds",
+            B::List(vec![B::str(&format!("http://127.0.0.1:{}/", seed_port))]),
+        ),
+        ("info", B::Dict(info)),
+    ]);
+    let torrent = write_torrent(&dir, "multi", torrent);
+
+    let out = dir.join("out");
+    let port = free_port();
+    let _client = spawn(&[
+        "serve-http",
+        torrent.to_str().unwrap(),
+        "-d",
+        out.to_str().unwrap(),
+        "-p",
+        &free_port().to_string(),
+        "--http-port",
+        &port.to_string(),
+    ]);
+
+    let mut index = None;
+    wait_for(Duration::from_secs(10), || {
+        index = http_request(port, "/", &[]);
+        index.is_some()
+    });
+    let (_, index) = index.expect("server never started");
+    assert_eq!(index, b"/multi/a.bin\n/multi/sub dir/b.bin\n");
+
+    let (head, body) =
+        http_request(port, "/multi/sub%20dir/b.bin", &["Range: bytes=100-40099"]).unwrap();
+    assert!(head.starts_with("HTTP/1.1 206"), "{}", head);
+    assert!(
+        head.contains("content-range: bytes 100-40099/70000"),
+        "{}",
+        head
+    );
+    assert!(body == b[100..40100], "wrong range");
+
+    let (head, body) = http_request(port, "/multi/a.bin", &[]).unwrap();
+    assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+    assert!(body == a, "wrong file");
+
+    let (head, _) = http_request(port, "/multi/a.bin", &["Range: bytes=40000-"]).unwrap();
+    assert!(head.starts_with("HTTP/1.1 416"), "{}", head);
+    let (head, _) = http_request(port, "/multi/c.bin", &[]).unwrap();
+    assert!(head.starts_with("HTTP/1.1 404"), "{}", head);
+
+    std::fs::remove_dir_all(&dir).ok();
+}
@@ -0,0 +1,94 @@
+// runs the ntorrent binary against its own tracker
+mod common;
+
+use common::*;
+use std::thread::sleep;
+use std::time::Duration;
+
+#[test]
+fn test_download_through_tracker() {
+    let dir = temp_dir("download");
+    let seed = dir.join("seed");
+    let leech = dir.join("leech");
+    std::fs::create_dir_all(&seed).unwrap();
+    std::fs::create_dir_all(&leech).unwrap();
+
+    let data = random_data(300_000);
+    std::fs::write(seed.join("data.bin"), &data).unwrap();
+
+    let port = free_port().to_string();
+    let announce = format!("http://127.0.0.1:{}/announce", port);
+    let torrent = make_torrent(&dir, &announce, "data.bin", &data);
+    let torrent = torrent.to_str().unwrap();
+
+    let _tracker = spawn(&["tracker", "-p", &port, "-i", "5"]);
+    sleep(Duration::from_millis(500));
+
+    let seed_port = free_port().to_string();
+    let _seeder = spawn(&[torrent, "-d", seed.to_str().unwrap(), "-p", &seed_port]);
+
+    // the seeder shows up in scrapes once it has announced
+    let scraped = wait_for(Duration::from_secs(20), || {
+        let out = run(&["scrape", torrent]);
+        String::from_utf8_lossy(&out.stdout).contains("1 seeders, 0 leechers")
+    });
+    assert!(scraped, "seeder never announced");
+
+    let leech_port = free_port().to_string();
+    let _leecher = spawn(&[torrent, "-d", leech.to_str().unwrap(), "-p", &leech_port]);
+
+    let downloaded = wait_for(Duration::from_secs(60), || {
+        std::fs::read(leech.join("data.bin")).ok().as_ref() == Some(&data)
+    });
+    assert!(downloaded, "download did not complete");
+
+    // the leecher announces completed as soon as it finishes
+    let completed = wait_for(Duration::from_secs(20), || {
+        let out = run(&["scrape", torrent]);
+        String::from_utf8_lossy(&out.stdout).contains("2 seeders, 0 leechers, 1 completed")
+    });
+    assert!(completed, "completion was not announced");
+
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn test_whitelist() {
+    let dir = temp_dir("whitelist");
+    let whitelist = dir.join("whitelist");
+    std::fs::write(&whitelist, format!("{}\n", "ab".repeat(20))).unwrap();
+
+    let port = free_port();
+    let _tracker = spawn(&[
+        "tracker",
+        "-p",
+        &port.to_string(),
+        "-w",
+        whitelist.to_str().unwrap(),
+    ]);
+
+    let announce = |hash: &str| {
+        let path = format!(
+            "/announce?info_hash={}&peer_id={}&port=6881&left=0",
+            hash,
+            "%01".repeat(20)
+        );
+        let mut res = Vec::new();
+        wait_for(Duration::from_secs(10), || {
+            res = http_get(port, &path);
+            !res.is_empty()
+        });
+        String::from_utf8_lossy(&res).into_owned()
+    };
+
+    let res = announce(&"%AB".repeat(20));
+    assert!(res.ends_with("min intervali900e5:peerslee"), "{}", res);
+    let res = announce(&"%CD".repeat(20));
+    assert!(
+        res.ends_with("d14:failure reason19:torrent not trackede"),
+        "{}",
+        res
+    );
+
+    std::fs::remove_dir_all(&dir).ok();
+}
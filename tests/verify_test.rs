@@ -0,0 +1,165 @@
+// checks downloaded files against the piece hashes
+mod common;
+
+use common::*;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[test]
+fn test_verify() {
+    let dir = temp_dir("verify");
+    // pieces: 0 in a, 1 in a and b, 2 and 3 in b
+    let a = random_data(40_000);
+    let b = random_data(70_000);
+    let all = [a.clone(), b.clone()].concat();
+
+    let requested = Arc::new(Mutex::new(Vec::new()));
+    let log = Arc::clone(&requested);
+    let seed_port =
+        http_server(
+            move |req| match req.param("piece").and_then(|p| p.parse::<usize>().ok()) {
+                Some(i) if i * PIECE_LENGTH < all.len() => {
+                    log.lock().unwrap().push(i);
+                    let end = all.len().min((i + 1) * PIECE_LENGTH);
+                    Response::new("200 OK", all[i * PIECE_LENGTH..end].to_vec())
+                }
+                _ => Response::new("404 Not Found", Vec::new()),
+            },
+        );
+
+    let info = multi_info("multi", &[(&["a.bin"], &a), (&["b.bin"], &b)]);
+    let torrent = B::Dict(vec![
+        ("announce", B::str("http://127.0.0.1:1/announce")),
+        (
+            "httpseeds",
+            B::List(vec![B::str(&format!("http://127.0.0.1:{}/", seed_port))]),
+        ),
+        ("info", B::Dict(info)),
+    ]);
+    let torrent = write_torrent(&dir, "multi", torrent);
+    let torrent = torrent.to_str().unwrap();
+
+    let out = dir.join("out");
+    std::fs::create_dir_all(out.join("multi")).unwrap();
+    std::fs::write(out.join("multi/a.bin"), &a).unwrap();
+    let mut bad = b.clone();
+    bad[50_000] ^= 1;
+    std::fs::write(out.join("multi/b.bin"), &bad).unwrap();
+
+    let res = run(&["verify", torrent, "-d", out.to_str().unwrap()]);
+    let stdout = String::from_utf8_lossy(&res.stdout);
+    assert!(!res.status.success());
+    assert!(
+        stdout.contains("3 of 4 pieces good, 0 missing, 1 corrupt"),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains("multi/b.bin (pieces 2)"), "{}", stdout);
+
+    // a missing file doesn't take the other one down with it
+    std::fs::remove_file(out.join("multi/a.bin")).unwrap();
+    let res = run(&["verify", torrent, "-d", out.to_str().unwrap()]);
+    let stdout = String::from_utf8_lossy(&res.stdout);
+    assert!(
+        stdout.contains("1 of 4 pieces good, 2 missing, 1 corrupt"),
+        "{}",
+        stdout
+    );
+
+    // only the missing and corrupt pieces are fetched
+    let _client = spawn(&[
+        torrent,
+        "-d",
+        out.to_str().unwrap(),
+        "-p",
+        &free_port().to_string(),
+    ]);
+    let done = wait_for(Duration::from_secs(30), || {
+        std::fs::read(out.join("multi/a.bin")).ok().as_ref() == Some(&a)
+            && std::fs::read(out.join("multi/b.bin")).ok().as_ref() == Some(&b)
+    });
+    assert!(done, "download did not complete");
+    let mut requested = requested.lock().unwrap().clone();
+    requested.sort();
+    assert_eq!(requested, vec![0, 1, 2]);
+
+    let res = run(&["verify", torrent, "-d", out.to_str().unwrap()]);
+    assert!(res.status.success());
+
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn test_verify_selection() {
+    let dir = temp_dir("verify_selection");
+    // pieces: 0 in a, 1 in a and b, 2 and 3 in b
+    let a = random_data(40_000);
+    let b = random_data(70_000);
+    let all = [a.clone(), b.clone()].concat();
+
+    let seed_port =
+        http_server(
+            move |req| match req.param("piece").and_then(|p| p.parse::<usize>().ok()) {
+                Some(i) if i * PIECE_LENGTH < all.len() => {
+                    let end = all.len().min((i + 1) * PIECE_LENGTH);
+                    Response::new("200 OK", all[i * PIECE_LENGTH..end].to_vec())
+                }
+                _ => Response::new("404 Not Found", Vec::new()),
+            },
+        );
+
+    let info = multi_info("multi", &[(&["a.bin"], &a), (&["b.bin"], &b)]);
+    let torrent = B::Dict(vec![
+        ("announce", B::str("http://127.0.0.1:1/announce")),
+        (
+            "httpseeds",
+            B::List(vec![B::str(&format!("http://127.0.0.1:{}/", seed_port))]),
+        ),
+        ("info", B::Dict(info)),
+    ]);
+    let torrent = write_torrent(&dir, "multi", torrent);
+    let torrent = torrent.to_str().unwrap();
+
+    let out = dir.join("out");
+    {
+        let _client = spawn(&[
+            torrent,
+            "-d",
+            out.to_str().unwrap(),
+            "-p",
+            &free_port().to_string(),
+            "--only",
+            "0",
+        ]);
+        let done = wait_for(Duration::from_secs(30), || {
+            std::fs::read(out.join("multi/a.bin")).ok().as_ref() == Some(&a)
+                && out.join("multi.resume").exists()
+        });
+        assert!(done, "download did not complete");
+    }
+
+    // the skipped file isn't needed and isn't created
+    let res = run(&[
+        "verify",
+        torrent,
+        "-d",
+        out.to_str().unwrap(),
+        "--only",
+        "0",
+    ]);
+    let stdout = String::from_utf8_lossy(&res.stdout);
+    assert!(res.status.success(), "{}", stdout);
+    assert!(
+        stdout.contains("2 of 4 pieces good, 2 missing"),
+        "{}",
+        stdout
+    );
+    assert!(!out.join("multi/b.bin").exists());
+
+    // without the selection it is wanted, but still left alone
+    let res = run(&["verify", torrent, "-d", out.to_str().unwrap()]);
+    assert!(!res.status.success());
+    assert!(!out.join("multi/b.bin").exists());
+
+    std::fs::remove_dir_all(&dir).ok();
+}
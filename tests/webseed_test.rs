@@ -0,0 +1,238 @@
+// downloads from a web seed (BEP 19) served by a local HTTP server
+mod common;
+
+use common::*;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+
+#[test]
+fn test_web_seed() {
+    let dir = temp_dir("webseed");
+    let a = random_data(40_000);
+    let b = random_data(70_000);
+    let c = random_data(5);
+
+    let mut served = HashMap::new();
+    served.insert("/files/multi/a.bin".to_string(), a.clone());
+    served.insert("/files/multi/sub%20dir/b.bin".to_string(), b.clone());
+    served.insert("/files/multi/c.bin".to_string(), c.clone());
+
+    // the first request fails so the seed has to retry
+    let mut failed = false;
+    let port = http_server(move |req| {
+        if !failed {
+            failed = true;
+            return Response::new("503 Service Unavailable", Vec::new());
+        }
+
+        match served.get(&req.path) {
+            Some(data) => range_response(req, data),
+            None => Response::new("404 Not Found", Vec::new()),
+        }
+    });
+
+    let info = multi_info(
+        "multi",
+        &[
+            (&["a.bin"], &a),
+            (&["sub dir", "b.bin"], &b),
+            (&["c.bin"], &c),
+        ],
+    );
+    let torrent = B::Dict(vec![
+        // no tracker, so the web seed is the only source
+        ("announce", B::str("http://127.0.0.1:1/announce")),
+        ("info", B::Dict(info)),
+        (
+            "url-list",
+            B::str(&format!("http://127.0.0.1:{}/files/", port)),
+        ),
+    ]);
+    let torrent = write_torrent(&dir, "multi", torrent);
+
+    let out = dir.join("out");
+    let _leecher = spawn(&[
+        torrent.to_str().unwrap(),
+        "-d",
+        out.to_str().unwrap(),
+        "-p",
+        &free_port().to_string(),
+    ]);
+
+    let done = wait_for(Duration::from_secs(60), || {
+        std::fs::read(out.join("multi/c.bin")).ok().as_ref() == Some(&c)
+    });
+    assert!(done, "download did not complete");
+    assert_eq!(std::fs::read(out.join("multi/a.bin")).unwrap(), a);
+    assert_eq!(std::fs::read(out.join("multi/sub dir/b.bin")).unwrap(), b);
+
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn test_http_seed() {
+    let dir = temp_dir("httpseed");
+    let data = random_data(100_000);
+
+    // the script is busy for its first request
+    let mut busy = true;
+    let served = data.clone();
+    let port = http_server(move |req| {
+        if busy {
+            busy = false;
+            return Response::new("503 Service Unavailable", b"2".to_vec());
+        }
+
+        if req.param("info_hash").is_none() {
+            return Response::new("400 Bad Request", Vec::new());
+        }
+        match req.param("piece").and_then(|p| p.parse::<usize>().ok()) {
+            Some(i) if i * PIECE_LENGTH < served.len() => {
+                let end = served.len().min((i + 1) * PIECE_LENGTH);
+                Response::new("200 OK", served[i * PIECE_LENGTH..end].to_vec())
+            }
+            _ => Response::new("404 Not Found", Vec::new()),
+        }
+    });
+
+    let torrent = B::Dict(vec![
+        ("announce", B::str("http://127.0.0.1:1/announce")),
+        (
+            "httpseeds",
+            B::List(vec![B::str(&format!("http://127.0.0.1:{}/seed", port))]),
+        ),
+        ("info", B::Dict(single_info("data.bin", &data))),
+    ]);
+    let torrent = write_torrent(&dir, "data", torrent);
+
+    let out = dir.join("out");
+    let _leecher = spawn(&[
+        torrent.to_str().unwrap(),
+        "-d",
+        out.to_str().unwrap(),
+        "-p",
+        &free_port().to_string(),
+    ]);
+
+    let done = wait_for(Duration::from_secs(30), || {
+        std::fs::read(out.join("data.bin")).ok().as_ref() == Some(&data)
+    });
+    assert!(done, "download did not complete");
+
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn test_http_seed_ranges() {
+    let dir = temp_dir("httpseed_ranges");
+    let data = random_data(2 * PIECE_LENGTH);
+    let block = PIECE_LENGTH / 2;
+
+    let asked = Arc::new(Mutex::new(Vec::new()));
+    let log = Arc::clone(&asked);
+    let served = data.clone();
+    let port = http_server(move |req| {
+        let i: usize = match req.param("piece").and_then(|p| p.parse().ok()) {
+            Some(i) if i < 2 => i,
+            _ => return Response::new("404 Not Found", Vec::new()),
+        };
+        let piece = &served[i * PIECE_LENGTH..(i + 1) * PIECE_LENGTH];
+        let ranges = req.param("ranges").map(|r| r.to_string());
+        log.lock().unwrap().push((i, ranges.clone()));
+
+        let body = match ranges {
+            Some(ranges) => ranges
+                .split(',')
+                .filter_map(|r| r.split_once('-'))
+                .flat_map(|(a, b)| {
+                    let (a, b): (usize, usize) = (a.parse().unwrap(), b.parse().unwrap());
+                    piece[a..=b].to_vec()
+                })
+                .collect(),
+            None => piece.to_vec(),
+        };
+        Response::new("200 OK", body)
+    });
+
+    let info = single_info("data.bin", &data);
+    let mut hash = Sha1::new();
+    hash.input(B::Dict(single_info("data.bin", &data)).encode());
+    let info_hash = hash.result().to_vec();
+    let torrent = B::Dict(vec![
+        ("announce", B::str("http://127.0.0.1:1/announce")),
+        (
+            "httpseeds",
+            B::List(vec![B::str(&format!("http://127.0.0.1:{}/seed", port))]),
+        ),
+        ("info", B::Dict(info)),
+    ]);
+    let torrent = write_torrent(&dir, "data", torrent);
+
+    // a peer left the first block of piece 0 behind last time
+    let out = dir.join("out");
+    std::fs::create_dir_all(&out).unwrap();
+    let file = out.join("data.bin");
+    let mut partial = vec![0; data.len()];
+    partial[..block].copy_from_slice(&data[..block]);
+    std::fs::write(&file, &partial).unwrap();
+    let mtime = std::fs::metadata(&file)
+        .unwrap()
+        .modified()
+        .unwrap()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let state = |length: usize, mtime: u64| {
+        B::Dict(vec![
+            ("length", B::Int(length as i64)),
+            ("mtime", B::Int(mtime as i64)),
+        ])
+    };
+    let resume = B::Dict(vec![
+        ("info hash", B::Str(info_hash)),
+        ("bitfield", B::Str(vec![0])),
+        ("files", B::List(vec![state(data.len(), mtime)])),
+        ("part", state(0, 0)),
+        ("part files", B::List(Vec::new())),
+        (
+            "unfinished",
+            B::List(vec![B::Dict(vec![
+                ("piece", B::Int(0)),
+                ("blocks", B::Str(vec![0b1000_0000])),
+            ])]),
+        ),
+        ("peers", B::List(Vec::new())),
+        (
+            "tracker",
+            B::Dict(vec![
+                ("announce", B::str("http://127.0.0.1:1/announce")),
+                ("key", B::str("00000000")),
+            ]),
+        ),
+        ("allocation", B::str("sparse")),
+    ]);
+    std::fs::write(out.join("data.bin.resume"), resume.encode()).unwrap();
+
+    let _leecher = spawn(&[
+        torrent.to_str().unwrap(),
+        "-d",
+        out.to_str().unwrap(),
+        "-p",
+        &free_port().to_string(),
+    ]);
+
+    let done = wait_for(Duration::from_secs(30), || {
+        std::fs::read(&file).ok().as_ref() == Some(&data)
+    });
+    assert!(done, "download did not complete");
+
+    // only the missing block of piece 0 was asked for
+    let asked = asked.lock().unwrap();
+    let range = format!("{}-{}", block, PIECE_LENGTH - 1);
+    assert_eq!(asked.iter().find(|(i, _)| *i == 0), Some(&(0, Some(range))));
+    assert_eq!(asked.iter().find(|(i, _)| *i == 1), Some(&(1, None)));
+
+    std::fs::remove_dir_all(&dir).ok();
+}